@@ -0,0 +1,216 @@
+//! JSON access log
+//!
+//! Writes one JSON object per line for every finished request, to stdout or
+//! to a file that is rotated by size. Lines are handed to a writer thread so
+//! that a slow disk never holds up a response. Fields named with
+//! `--access-log-redact` are replaced with `"[redacted]"`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+
+use anyhow::{bail, Context, Result};
+use log::error;
+use serde::Serialize;
+
+const REDACTED: &str = "[redacted]";
+
+/// Field names of [`AccessRecord`], for checking `--access-log-redact`
+const FIELDS: &[&str] = &[
+    "timestamp",
+    "request_id",
+    "client",
+    "client_ip",
+    "method",
+    "path",
+    "model",
+    "upstream",
+    "status",
+    "bytes_in",
+    "bytes_out",
+    "time_to_first_byte_ms",
+    "duration_ms",
+    "priority",
+    "queued_ms",
+    "streamed",
+    "attempts",
+    "prompt_tokens",
+    "completion_tokens",
+    "time_to_first_token_ms",
+    "max_chunk_gap_ms",
+    "tokens_per_second",
+    "outcome",
+];
+
+#[derive(Serialize, Debug)]
+pub struct AccessRecord {
+    /// When the request arrived, RFC 3339
+    pub timestamp: String,
+    pub request_id: String,
+    /// Name of the configured client, or the client address if it is not a known client
+    pub client: String,
+    pub client_ip: String,
+    pub method: String,
+    pub path: String,
+    pub model: Option<String>,
+    pub upstream: String,
+    pub status: u16,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub time_to_first_byte_ms: u64,
+    pub duration_ms: u64,
+    /// Priority class the request was scheduled in
+    pub priority: &'static str,
+    /// Time spent waiting for a slot under a concurrency limit
+    pub queued_ms: Option<u64>,
+    pub streamed: bool,
+    /// Times the request was sent to the remote, more than one if it was retried
+    pub attempts: u32,
+    /// Token counts from Ollama's final chunk, for chat and generate requests
+    pub prompt_tokens: Option<u64>,
+    pub completion_tokens: Option<u64>,
+    /// Time to the first chunk with generated output, for chat and generate requests
+    pub time_to_first_token_ms: Option<u64>,
+    /// Longest pause between chunks of a streamed chat or generate response
+    pub max_chunk_gap_ms: Option<u64>,
+    /// Generation throughput as reported by the remote
+    pub tokens_per_second: Option<f64>,
+    /// How the response body ended, see [`crate::body::Outcome`]
+    pub outcome: &'static str,
+}
+
+pub struct AccessLog {
+    lines: Sender<String>,
+    redact: Vec<String>,
+}
+
+impl AccessLog {
+    /// Opens the log at `destination`, which is `stdout` or a file path.
+    /// A file is rotated once it reaches `max_bytes`, keeping `max_files` old files.
+    pub fn open(destination: &str, max_bytes: u64, max_files: usize, redact: Vec<String>) -> Result<Self> {
+        for field in &redact {
+            if !FIELDS.contains(&field.as_str()) {
+                bail!(
+                    "Unknown access log field '{}' to redact (fields: {})",
+                    field,
+                    FIELDS.join(", ")
+                );
+            }
+        }
+
+        let mut sink = if destination == "stdout" {
+            Sink::Stdout
+        } else {
+            Sink::File(RotatingFile::open(PathBuf::from(destination), max_bytes, max_files)?)
+        };
+
+        let (lines, received) = mpsc::channel::<String>();
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                for line in received {
+                    if let Err(e) = sink.write_line(&line) {
+                        error!("Failed to write access log: {}", e);
+                    }
+                }
+            })
+            .context("Failed to start the access log writer")?;
+
+        Ok(AccessLog { lines, redact })
+    }
+
+    pub fn write(&self, record: &AccessRecord) {
+        let mut value = match serde_json::to_value(record) {
+            Ok(value) => value,
+            Err(e) => {
+                error!("Failed to serialize access log record: {}", e);
+                return;
+            }
+        };
+        if let Some(fields) = value.as_object_mut() {
+            for field in &self.redact {
+                if let Some(v) = fields.get_mut(field) {
+                    *v = REDACTED.into();
+                }
+            }
+        }
+        // The writer thread only goes away with the process
+        let _ = self.lines.send(value.to_string());
+    }
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+}
+
+impl Sink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Sink::Stdout => {
+                let mut stdout = io::stdout().lock();
+                writeln!(stdout, "{}", line)?;
+                stdout.flush()
+            }
+            Sink::File(file) => file.write_line(line),
+        }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> Result<Self> {
+        let file = open_append(&path)
+            .with_context(|| format!("Failed to open access log {}", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    // access.log -> access.log.1 -> access.log.2 ..., dropping the oldest
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                let _ = std::fs::rename(self.numbered(n), self.numbered(n + 1));
+            }
+            std::fs::rename(&self.path, self.numbered(1))?;
+            self.file = open_append(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn numbered(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        name.into()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
@@ -0,0 +1,87 @@
+//! Admin endpoints
+//!
+//! Served on a separate address from the proxy so that operational data
+//! never mixes with the Ollama API surface.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+
+use crate::{metrics, AppState};
+
+async fn admin_handler(
+    req: Request<Body>,
+    state: Arc<AppState>,
+) -> Result<Response<Body>, hyper::Error> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/keys") => {
+            // Per-key counters for every profile that uses a key pool
+            let mut stats = BTreeMap::new();
+            if let Some(pool) = state.auth.key_pool() {
+                stats.insert(state.args.profile.clone(), pool.stats());
+            }
+            for (profile, auth) in &state.profile_auth {
+                if let Some(pool) = auth.key_pool() {
+                    stats.insert(profile.clone(), pool.stats());
+                }
+            }
+            Ok(json_response(&stats))
+        }
+        (&Method::GET, "/usage") => Ok(json_response(&state.usage.snapshot())),
+        (&Method::GET, "/budgets") => Ok(json_response(&state.budgets.status())),
+        (&Method::GET, "/metrics") => match state.metrics.render() {
+            Ok(body) => Ok(Response::builder()
+                .header(hyper::header::CONTENT_TYPE, metrics::content_type())
+                .body(Body::from(body))
+                .unwrap()),
+            Err(e) => {
+                error!("Failed to render metrics: {:#}", e);
+                let mut response = Response::new(Body::from("Internal Server Error"));
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                Ok(response)
+            }
+        },
+        _ => {
+            let mut response = Response::new(Body::from("Not Found"));
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            Ok(response)
+        }
+    }
+}
+
+pub fn json_response<T: serde::Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_vec_pretty(value) {
+        Ok(body) => Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(e) => {
+            error!("Failed to serialize admin response: {}", e);
+            let mut response = Response::new(Body::from("Internal Server Error"));
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        }
+    }
+}
+
+/// Runs the admin server until the process exits
+pub async fn serve(addr: &str, state: Arc<AppState>) -> Result<()> {
+    let addr: SocketAddr = addr.parse().context("Failed to parse admin address")?;
+
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move { Ok::<_, hyper::Error>(service_fn(move |req| admin_handler(req, state.clone()))) }
+    });
+
+    let server = Server::try_bind(&addr)
+        .context("Failed to bind admin address")?
+        .serve(make_service);
+    info!("Admin server listening on http://{}", addr);
+
+    server.await.context("Admin server error")
+}
@@ -0,0 +1,117 @@
+//! Upstream authentication
+//!
+//! Decides how outgoing requests prove themselves to the remote: a bearer
+//! token from the key pool, a signature made with an Ollama ed25519 key, or
+//! an AWS SigV4 signature.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use hyper::header::{HeaderValue, AUTHORIZATION};
+use hyper::{HeaderMap, Request, StatusCode, Uri};
+
+use crate::body::ProxyBody;
+use crate::keypool::{KeyLease, KeyPool};
+use crate::ollama_auth::OllamaSigner;
+use crate::sigv4::SigV4Signer;
+
+/// How the proxy authenticates to the remote
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMode {
+    /// `Authorization: Bearer <api key>`, rotating over all configured keys
+    Bearer,
+    /// Sign requests with an Ollama ed25519 key, as the Ollama client does for ollama.com
+    OllamaKey,
+    /// Sign requests with AWS Signature Version 4, for remotes behind API Gateway IAM auth
+    Sigv4,
+}
+
+pub enum UpstreamAuth {
+    None,
+    Bearer(KeyPool),
+    OllamaKey(OllamaSigner),
+    SigV4(SigV4Signer),
+}
+
+/// Why a request could not be authenticated
+pub enum AuthError {
+    /// Every key is benched; retry after the given duration
+    Exhausted(Duration),
+    Failed(anyhow::Error),
+}
+
+impl UpstreamAuth {
+    pub fn key_pool(&self) -> Option<&KeyPool> {
+        match self {
+            UpstreamAuth::Bearer(pool) => Some(pool),
+            _ => None,
+        }
+    }
+
+    /// Short description for the startup log, never containing secrets
+    pub fn describe(&self) -> String {
+        match self {
+            UpstreamAuth::None => "disabled".to_string(),
+            UpstreamAuth::Bearer(pool) => match pool.stats().len() {
+                1 => "bearer token".to_string(),
+                n => format!("bearer token ({} keys, round-robin)", n),
+            },
+            UpstreamAuth::OllamaKey(signer) => format!("ollama key {}", signer.public_key()),
+            UpstreamAuth::SigV4(signer) => signer.describe(),
+        }
+    }
+
+    /// Adds credentials to an outgoing request
+    pub async fn apply(
+        &self,
+        mut req: Request<ProxyBody>,
+    ) -> Result<(Request<ProxyBody>, Option<KeyLease>), AuthError> {
+        match self {
+            UpstreamAuth::None => Ok((req, None)),
+            UpstreamAuth::Bearer(pool) => {
+                let lease = pool.acquire().map_err(AuthError::Exhausted)?;
+                let value = HeaderValue::from_str(&format!("Bearer {}", pool.secret(lease)))
+                    .map_err(|e| AuthError::Failed(anyhow!("Invalid API key: {}", e)))?;
+                req.headers_mut().insert(AUTHORIZATION, value);
+                Ok((req, Some(lease)))
+            }
+            UpstreamAuth::OllamaKey(signer) => {
+                let uri = req.uri().clone();
+                let (path_and_query, authorization) =
+                    signer.sign(req.method().as_str(), uri.path(), uri.query());
+
+                let mut parts = uri.into_parts();
+                parts.path_and_query = Some(
+                    path_and_query
+                        .parse()
+                        .map_err(|e| AuthError::Failed(anyhow!("Invalid signed path: {}", e)))?,
+                );
+                *req.uri_mut() = Uri::from_parts(parts)
+                    .map_err(|e| AuthError::Failed(anyhow!("Invalid signed URI: {}", e)))?;
+
+                let value = HeaderValue::from_str(&authorization)
+                    .map_err(|e| AuthError::Failed(anyhow!("Invalid signature: {}", e)))?;
+                req.headers_mut().insert(AUTHORIZATION, value);
+                Ok((req, None))
+            }
+            UpstreamAuth::SigV4(signer) => {
+                let req = signer.sign_request(req).await.map_err(AuthError::Failed)?;
+                Ok((req, None))
+            }
+        }
+    }
+
+    /// Feeds the upstream status back, benching keys that were throttled or rejected.
+    /// Returns the label of a benched key and how long it is benched for.
+    pub fn report(
+        &self,
+        lease: Option<KeyLease>,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> Option<(&str, Duration)> {
+        let (pool, lease) = (self.key_pool()?, lease?);
+        let bench_for = pool.report(lease, status, headers)?;
+        Some((pool.label(lease), bench_for))
+    }
+}
@@ -0,0 +1,274 @@
+//! Request and response bodies
+//!
+//! Bodies are streamed through the proxy rather than collected. When a
+//! feature needs the model, the start of a request body is read up front so
+//! fields such as `model` can be looked at, and response bodies can be
+//! tapped to watch each chunk go by and learn how the stream ended.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use http_body::combinators::UnsyncBoxBody;
+use http_body::SizeHint;
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, HeaderMap};
+
+/// A body relayed by the proxy, which may have been tapped or limited on the way
+pub type ProxyBody = UnsyncBoxBody<Bytes, BoxError>;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Boxes a body so it can be relayed
+pub fn boxed<B>(body: B) -> ProxyBody
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    body.map_err(Into::into).boxed_unsync()
+}
+
+/// How much of a request body is read before it is forwarded
+pub const INSPECT_LIMIT: usize = 1024 * 1024;
+
+/// How a tapped body ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Every chunk was relayed
+    Completed,
+    /// The other side sent an error part way through
+    Failed,
+    /// The body was dropped before it ended, usually because the client went away
+    Dropped,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Completed => "completed",
+            Outcome::Failed => "failed",
+            Outcome::Dropped => "dropped",
+        }
+    }
+}
+
+/// Watches a body as it is relayed
+pub trait BodyObserver: Send + 'static {
+    fn on_chunk(&mut self, _chunk: &Bytes) {}
+
+    /// Called exactly once, when the body ends or is dropped
+    fn on_end(&mut self, _outcome: Outcome) {}
+}
+
+/// Whether requests to this path name a model in their body; blob uploads are
+/// large and never do
+pub fn carries_model(path: &str) -> bool {
+    !path.starts_with("/api/blobs/")
+}
+
+/// A request body whose beginning has already been read
+pub struct InspectedBody {
+    prefix: Bytes,
+    // The unread remainder; `None` when the whole body fit in the prefix
+    rest: Option<Body>,
+}
+
+impl InspectedBody {
+    /// Reads from `body` until it ends or at least `limit` bytes are in hand
+    pub async fn read(mut body: Body, limit: usize) -> Result<Self, hyper::Error> {
+        let mut prefix = Vec::new();
+        while prefix.len() < limit {
+            match body.data().await {
+                Some(chunk) => prefix.extend_from_slice(&chunk?),
+                None => {
+                    return Ok(InspectedBody {
+                        prefix: prefix.into(),
+                        rest: None,
+                    })
+                }
+            }
+        }
+        Ok(InspectedBody {
+            prefix: prefix.into(),
+            rest: Some(body),
+        })
+    }
+
+    /// A body left unread, with no model to be found
+    pub fn unread(body: Body) -> Self {
+        InspectedBody {
+            prefix: Bytes::new(),
+            rest: Some(body),
+        }
+    }
+
+    /// The `model` field of a JSON body
+    pub fn model(&self) -> Option<String> {
+        if self.rest.is_none() {
+            let value: serde_json::Value = serde_json::from_slice(&self.prefix).ok()?;
+            return value.get("model")?.as_str().map(str::to_string);
+        }
+        // Too big to parse whole; Ollama clients send the model near the start
+        scan_string_field(&self.prefix, "model")
+    }
+
+    /// Whether a chat or generate request asks for a streamed response, as it
+    /// does unless `"stream": false`; assumed for bodies too big to parse
+    pub fn streams(&self) -> bool {
+        if self.rest.is_some() {
+            return true;
+        }
+        serde_json::from_slice::<serde_json::Value>(&self.prefix)
+            .ok()
+            .and_then(|value| value.get("stream")?.as_bool())
+            .unwrap_or(true)
+    }
+
+    /// Turns this back into a body, counting the bytes read from the client in `received`
+    pub fn into_body(self, received: &Arc<AtomicU64>) -> ProxyBody {
+        received.fetch_add(self.prefix.len() as u64, Ordering::Relaxed);
+        match self.rest {
+            None => boxed(Body::from(self.prefix)),
+            Some(rest) if self.prefix.is_empty() => tap(rest, ByteCounter(received.clone())),
+            Some(rest) => boxed(Prefixed {
+                prefix: Some(self.prefix),
+                rest: tap(rest, ByteCounter(received.clone())),
+            }),
+        }
+    }
+}
+
+// Finds `"field": "value"` in a possibly truncated JSON document
+fn scan_string_field(json: &[u8], field: &str) -> Option<String> {
+    let text = String::from_utf8_lossy(json);
+    let key = format!("\"{}\"", field);
+    let after_key = &text[text.find(&key)? + key.len()..];
+    let value = after_key.trim_start().strip_prefix(':')?.trim_start().strip_prefix('"')?;
+    let end = value.find('"')?;
+    Some(value[..end].to_string())
+}
+
+struct ByteCounter(Arc<AtomicU64>);
+
+impl BodyObserver for ByteCounter {
+    fn on_chunk(&mut self, chunk: &Bytes) {
+        self.0.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+    }
+}
+
+// The start of a body that was read up front, put back in front of the rest
+struct Prefixed {
+    prefix: Option<Bytes>,
+    rest: ProxyBody,
+}
+
+impl HttpBody for Prefixed {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, BoxError>>> {
+        match self.prefix.take() {
+            Some(prefix) => Poll::Ready(Some(Ok(prefix))),
+            None => Pin::new(&mut self.rest).poll_data(cx),
+        }
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, BoxError>> {
+        Pin::new(&mut self.rest).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.prefix.is_none() && self.rest.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let prefix = self.prefix.as_ref().map_or(0, |prefix| prefix.len() as u64);
+        let rest = self.rest.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(rest.lower() + prefix);
+        if let Some(upper) = rest.upper() {
+            hint.set_upper(upper + prefix);
+        }
+        hint
+    }
+}
+
+/// Wraps `body` so that `observer` sees every chunk and the end of the stream
+pub fn tap<B>(body: B, observer: impl BodyObserver) -> ProxyBody
+where
+    B: HttpBody<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: Into<BoxError>,
+{
+    boxed(Tapped {
+        inner: body,
+        observer: Some(Box::new(observer)),
+    })
+}
+
+struct Tapped<B: HttpBody> {
+    inner: B,
+    // Taken when the body ends so that `on_end` runs only once
+    observer: Option<Box<dyn BodyObserver>>,
+}
+
+impl<B: HttpBody> Tapped<B> {
+    fn finish(&mut self, outcome: Outcome) {
+        if let Some(mut observer) = self.observer.take() {
+            observer.on_end(outcome);
+        }
+    }
+}
+
+impl<B: HttpBody<Data = Bytes> + Unpin> HttpBody for Tapped<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, B::Error>>> {
+        match Pin::new(&mut self.inner).poll_data(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some(observer) = self.observer.as_mut() {
+                    observer.on_chunk(&chunk);
+                }
+                // A body of known length isn't polled again once it has all gone out
+                if self.inner.is_end_stream() {
+                    self.finish(Outcome::Completed);
+                }
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Some(Err(err))) => {
+                self.finish(Outcome::Failed);
+                Poll::Ready(Some(Err(err)))
+            }
+            Poll::Ready(None) => {
+                self.finish(Outcome::Completed);
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, B::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<B: HttpBody> Drop for Tapped<B> {
+    fn drop(&mut self) {
+        // An empty body may never be polled at all
+        let outcome = if self.inner.is_end_stream() {
+            Outcome::Completed
+        } else {
+            Outcome::Dropped
+        };
+        self.finish(outcome);
+    }
+}
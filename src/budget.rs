@@ -0,0 +1,621 @@
+//! Token budgets
+//!
+//! Budgets cap the tokens a client, a model, or both may use per calendar
+//! hour, day, week or month (UTC). A request is refused with a 429 once a
+//! budget that covers it is used up, and a warning is logged once per period
+//! when usage crosses the budget's `warn_at` fraction. Requests still under
+//! way count against a budget with the average tokens of a request so far,
+//! until their own usage is known, so that many at once can't all slip in
+//! under the limit. Usage so far in the current period is read back from
+//! the usage database at startup, so restarting the proxy does not reset
+//! any budget.
+//!
+//! ```toml
+//! # 2M completion tokens per day for each client
+//! [[budgets]]
+//! client = "*"
+//! tokens = "completion"
+//! limit = 2000000
+//! period = "day"
+//!
+//! # Everyone together may use 50M tokens of the 70B model per month
+//! [[budgets]]
+//! name = "70b-monthly"
+//! model = "llama3.1:70b"
+//! limit = 50000000
+//! period = "month"
+//! warn_at = 0.9
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::ledger;
+use crate::usage::Usage;
+
+/// Matches every client or model, with a separate allowance for each
+const EACH: &str = "*";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BudgetConfig {
+    /// Name used in errors and logs [default: a description of the budget]
+    pub name: Option<String>,
+    /// Client name, or "*" for each client separately [default: all clients together]
+    pub client: Option<String>,
+    /// Model name, or "*" for each model separately [default: all models together]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub tokens: TokenKind,
+    pub limit: u64,
+    pub period: Period,
+    /// Fraction of the limit at which to log a warning
+    #[serde(default = "default_warn_at")]
+    pub warn_at: f64,
+}
+
+fn default_warn_at() -> f64 {
+    0.8
+}
+
+/// Which tokens count against a budget
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenKind {
+    Prompt,
+    Completion,
+    #[default]
+    Total,
+}
+
+impl TokenKind {
+    fn count(self, usage: &Usage) -> u64 {
+        match self {
+            TokenKind::Prompt => usage.prompt_eval_count,
+            TokenKind::Completion => usage.eval_count,
+            TokenKind::Total => usage.prompt_eval_count + usage.eval_count,
+        }
+    }
+
+    fn sql(self) -> &'static str {
+        match self {
+            TokenKind::Prompt => "prompt_tokens",
+            TokenKind::Completion => "completion_tokens",
+            TokenKind::Total => "prompt_tokens + completion_tokens",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+impl Period {
+    fn noun(self) -> &'static str {
+        match self {
+            Period::Hour => "hour",
+            Period::Day => "day",
+            Period::Week => "week",
+            Period::Month => "month",
+        }
+    }
+
+    /// Start of the period containing `now`, in seconds since the Unix epoch
+    fn start(self, now: u64) -> u64 {
+        let day = now / 86400;
+        match self {
+            Period::Hour => now - now % 3600,
+            Period::Day => day * 86400,
+            // 1970-01-01 was a Thursday; weeks start on Monday
+            Period::Week => (day - (day + 3) % 7) * 86400,
+            Period::Month => {
+                let (year, month, _) = civil_from_days(day);
+                days_from_civil(year, month, 1) * 86400
+            }
+        }
+    }
+
+    /// Start of the period after the one starting at `start`
+    fn next(self, start: u64) -> u64 {
+        match self {
+            Period::Hour => start + 3600,
+            Period::Day => start + 86400,
+            Period::Week => start + 7 * 86400,
+            Period::Month => {
+                let (year, month, _) = civil_from_days(start / 86400);
+                let (year, month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+                days_from_civil(year, month, 1) * 86400
+            }
+        }
+    }
+}
+
+// Converts days since 1970-01-01 to (year, month, day), after Howard Hinnant's algorithm
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// A budget that has no tokens left for a request
+pub struct Exhausted {
+    pub name: String,
+    /// How long until the budget resets
+    pub resets_in: Duration,
+}
+
+/// Current state of one budget, for `/budgets` on the admin address
+#[derive(Serialize)]
+pub struct BudgetStatus {
+    pub name: String,
+    pub client: String,
+    pub model: String,
+    pub used: u64,
+    pub limit: u64,
+    pub resets_at: String,
+}
+
+// Usage of one budget for one client/model in the current period
+struct Counter {
+    period_start: u64,
+    used: u64,
+    // Requests that used tokens, and the tokens held for those still under way
+    requests: u64,
+    reserved: u64,
+    warned: bool,
+}
+
+impl Counter {
+    fn new(period_start: u64) -> Self {
+        Counter {
+            period_start,
+            used: 0,
+            requests: 0,
+            reserved: 0,
+            warned: false,
+        }
+    }
+
+    // What a request is expected to use, going by the ones before it
+    fn estimate(&self) -> u64 {
+        self.used.checked_div(self.requests).unwrap_or(0)
+    }
+}
+
+type CounterKey = (usize, String, String);
+
+/// Tokens held for a request under the budgets covering it, until it is settled
+#[must_use]
+#[derive(Default)]
+pub struct Reservation {
+    holds: Vec<(CounterKey, u64, u64)>,
+}
+
+pub struct Budgets {
+    budgets: Vec<(String, BudgetConfig)>,
+    // By budget index, client and model ("" where the budget is shared)
+    counters: Mutex<HashMap<CounterKey, Counter>>,
+}
+
+impl Budgets {
+    /// Sets up the budgets, counting the usage already recorded in the current periods
+    pub fn load(configs: &[BudgetConfig], usage_db: Option<&Path>) -> Result<Self> {
+        let mut budgets = Vec::new();
+        for config in configs {
+            if !(0.0..=1.0).contains(&config.warn_at) {
+                bail!("Budget warn_at must be between 0 and 1, got {}", config.warn_at);
+            }
+            budgets.push((describe(config), config.clone()));
+        }
+        let budgets = Budgets {
+            budgets,
+            counters: Mutex::new(HashMap::new()),
+        };
+        if budgets.budgets.is_empty() {
+            return Ok(budgets);
+        }
+
+        let Some(usage_db) = usage_db else {
+            bail!("Budgets need --usage-db so that usage survives restarts");
+        };
+        let conn = ledger::open(usage_db)?;
+        let now = unix_secs(SystemTime::now());
+        let mut counters = budgets.counters.lock().unwrap();
+        for (index, (name, budget)) in budgets.budgets.iter().enumerate() {
+            let period_start = budget.period.start(now);
+            let (client_key, client_name) = key_sql("client", budget.client.as_deref());
+            let (model_key, model_name) = key_sql("model", budget.model.as_deref());
+            let sql = format!(
+                "SELECT {client_key}, {model_key}, SUM({tokens}), COUNT(*) FROM usage
+                 WHERE timestamp_ms >= ?1 AND (?2 IS NULL OR client = ?2) AND (?3 IS NULL OR model = ?3)
+                 GROUP BY 1, 2",
+                tokens = budget.tokens.sql(),
+            );
+
+            let mut stmt = conn.prepare(&sql)?;
+            let params = rusqlite::params![period_start as i64 * 1000, client_name, model_name];
+            let rows = stmt.query_map(params, |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?,
+                ))
+            })?;
+            for row in rows {
+                let (client, model, used, requests) = row?;
+                info!("Budget '{}': {} tokens used this {}", name, used, budget.period.noun());
+                counters.insert(
+                    (index, client, model),
+                    Counter {
+                        period_start,
+                        used: used as u64,
+                        requests: requests as u64,
+                        reserved: 0,
+                        // Don't warn again for a threshold crossed before the restart
+                        warned: used as f64 >= budget.limit as f64 * budget.warn_at,
+                    },
+                );
+            }
+        }
+        drop(counters);
+        Ok(budgets)
+    }
+
+    /// Holds tokens for this request under every budget covering it, or finds
+    /// one that has nothing left, counting the requests still under way
+    pub fn check(&self, client: &str, model: Option<&str>) -> Result<Reservation, Exhausted> {
+        self.check_at(client, model, unix_secs(SystemTime::now()))
+    }
+
+    fn check_at(&self, client: &str, model: Option<&str>, now: u64) -> Result<Reservation, Exhausted> {
+        let mut counters = self.counters.lock().unwrap();
+        let mut keys = Vec::new();
+        for (index, (name, budget)) in self.budgets.iter().enumerate() {
+            let Some(key) = counter_key(index, budget, client, model) else {
+                continue;
+            };
+            let counter = current(&mut counters, key.clone(), budget.period, now);
+            if counter.used + counter.reserved >= budget.limit {
+                return Err(Exhausted {
+                    name: name.clone(),
+                    resets_in: Duration::from_secs(budget.period.next(counter.period_start) - now),
+                });
+            }
+            keys.push(key);
+        }
+
+        let mut reservation = Reservation::default();
+        for key in keys {
+            let counter = counters.get_mut(&key).expect("counter was just looked up");
+            let tokens = counter.estimate();
+            counter.reserved += tokens;
+            reservation.holds.push((key, counter.period_start, tokens));
+        }
+        Ok(reservation)
+    }
+
+    /// Releases a request's reservation once it is over, counting the tokens
+    /// it used, if it got as far as using any
+    pub fn settle(&self, reservation: Reservation, request_id: &str, client: &str, usage: Option<&Usage>) {
+        self.settle_at(reservation, request_id, client, usage, unix_secs(SystemTime::now()))
+    }
+
+    fn settle_at(
+        &self,
+        reservation: Reservation,
+        request_id: &str,
+        client: &str,
+        usage: Option<&Usage>,
+        now: u64,
+    ) {
+        let mut counters = self.counters.lock().unwrap();
+        for (key, period_start, tokens) in reservation.holds {
+            let (name, budget) = &self.budgets[key.0];
+            let counter = current(&mut counters, key, budget.period, now);
+            // A reservation from a period that has since ended went with it
+            if counter.period_start == period_start {
+                counter.reserved = counter.reserved.saturating_sub(tokens);
+            }
+            let Some(usage) = usage else {
+                continue;
+            };
+            counter.used += budget.tokens.count(usage);
+            counter.requests += 1;
+
+            if !counter.warned && counter.used as f64 >= budget.limit as f64 * budget.warn_at {
+                counter.warned = true;
+                warn!(
+                    "[{}] Budget '{}' is at {} of {} tokens for client {}",
+                    request_id, name, counter.used, budget.limit, client
+                );
+            }
+        }
+    }
+
+    pub fn status(&self) -> Vec<BudgetStatus> {
+        let now = unix_secs(SystemTime::now());
+        let counters = self.counters.lock().unwrap();
+        let mut status: BTreeMap<(usize, &str, &str), BudgetStatus> = BTreeMap::new();
+        for ((index, client, model), counter) in counters.iter() {
+            let (name, budget) = &self.budgets[*index];
+            // Counters from a finished period are only reset when next used
+            let period_start = budget.period.start(now);
+            let used = if counter.period_start == period_start { counter.used } else { 0 };
+            let resets_at = UNIX_EPOCH + Duration::from_secs(budget.period.next(period_start));
+            status.insert(
+                (*index, client, model),
+                BudgetStatus {
+                    name: name.clone(),
+                    client: client.clone(),
+                    model: model.clone(),
+                    used,
+                    limit: budget.limit,
+                    resets_at: humantime::format_rfc3339_seconds(resets_at).to_string(),
+                },
+            );
+        }
+        status.into_values().collect()
+    }
+}
+
+// The counter a request uses for a budget, or None if the budget doesn't cover it
+fn counter_key(index: usize, budget: &BudgetConfig, client: &str, model: Option<&str>) -> Option<CounterKey> {
+    let client_key = match budget.client.as_deref() {
+        None => String::new(),
+        Some(EACH) => client.to_string(),
+        Some(name) if name == client => String::new(),
+        Some(_) => return None,
+    };
+    let model = model.unwrap_or("");
+    let model_key = match budget.model.as_deref() {
+        None => String::new(),
+        Some(EACH) => model.to_string(),
+        Some(name) if name == model => String::new(),
+        Some(_) => return None,
+    };
+    Some((index, client_key, model_key))
+}
+
+// The counter for the current period, starting a new one when the last has ended
+fn current(counters: &mut HashMap<CounterKey, Counter>, key: CounterKey, period: Period, now: u64) -> &mut Counter {
+    let period_start = period.start(now);
+    let counter = counters.entry(key).or_insert_with(|| Counter::new(period_start));
+    if counter.period_start != period_start {
+        *counter = Counter::new(period_start);
+    }
+    counter
+}
+
+// SQL for a budget's client or model column, matching how counters are keyed,
+// and the one name the column is limited to, if any
+fn key_sql<'a>(column: &'static str, scope: Option<&'a str>) -> (&'static str, Option<&'a str>) {
+    match scope {
+        None => ("''", None),
+        Some(EACH) => (column, None),
+        Some(name) => ("''", Some(name)),
+    }
+}
+
+fn describe(config: &BudgetConfig) -> String {
+    if let Some(name) = &config.name {
+        return name.clone();
+    }
+    let scope = |value: Option<&str>, noun: &str| match value {
+        None => None,
+        Some(EACH) => Some(format!("each {}", noun)),
+        Some(name) => Some(format!("{} {}", noun, name)),
+    };
+    let scopes: Vec<String> = [
+        scope(config.client.as_deref(), "client"),
+        scope(config.model.as_deref(), "model"),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    let tokens = match config.tokens {
+        TokenKind::Prompt => "prompt tokens",
+        TokenKind::Completion => "completion tokens",
+        TokenKind::Total => "tokens",
+    };
+    let mut description = format!("{} {} per {}", config.limit, tokens, config.period.noun());
+    if !scopes.is_empty() {
+        description = format!("{} for {}", description, scopes.join(", "));
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 3600;
+    const DAY: u64 = 86400;
+
+    #[test]
+    fn civil_dates_round_trip() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(days_from_civil(2000, 2, 29)), (2000, 2, 29));
+        for days in (0..200_000).step_by(7) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn civil_dates_know_leap_years() {
+        let length = |year, month| {
+            let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+            days_from_civil(next_year, next_month, 1) - days_from_civil(year, month, 1)
+        };
+        assert_eq!(length(2024, 2), 29);
+        assert_eq!(length(2023, 2), 28);
+        assert_eq!(length(2000, 2), 29);
+        assert_eq!(length(2100, 2), 28);
+        assert_eq!(length(2023, 12), 31);
+        assert_eq!(length(2023, 4), 30);
+    }
+
+    #[test]
+    fn periods_start_on_calendar_boundaries() {
+        // Thursday 2024-02-29 13:45:10 UTC
+        let day = days_from_civil(2024, 2, 29) * DAY;
+        let now = day + 13 * HOUR + 45 * 60 + 10;
+        assert_eq!(Period::Hour.start(now), day + 13 * HOUR);
+        assert_eq!(Period::Day.start(now), day);
+        assert_eq!(Period::Week.start(now), days_from_civil(2024, 2, 26) * DAY);
+        assert_eq!(Period::Month.start(now), days_from_civil(2024, 2, 1) * DAY);
+        // A Monday starts its own week
+        let monday = days_from_civil(2024, 2, 26) * DAY;
+        assert_eq!(Period::Week.start(monday), monday);
+        assert_eq!(Period::Week.start(monday - 1), days_from_civil(2024, 2, 19) * DAY);
+    }
+
+    #[test]
+    fn periods_roll_over() {
+        let start = days_from_civil(2024, 2, 1) * DAY;
+        assert_eq!(Period::Hour.next(start), start + HOUR);
+        assert_eq!(Period::Day.next(start), start + DAY);
+        assert_eq!(Period::Week.next(start), start + 7 * DAY);
+        assert_eq!(Period::Month.next(start), days_from_civil(2024, 3, 1) * DAY);
+        let december = days_from_civil(2023, 12, 1) * DAY;
+        assert_eq!(Period::Month.next(december), days_from_civil(2024, 1, 1) * DAY);
+        // The last second of a month still belongs to it
+        let end = days_from_civil(2024, 3, 1) * DAY - 1;
+        assert_eq!(Period::Month.start(end), start);
+    }
+
+    fn budgets(configs: &[BudgetConfig]) -> Budgets {
+        Budgets {
+            budgets: configs.iter().map(|config| (describe(config), config.clone())).collect(),
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn daily(client: Option<&str>, limit: u64) -> BudgetConfig {
+        BudgetConfig {
+            name: None,
+            client: client.map(str::to_string),
+            model: None,
+            tokens: TokenKind::Total,
+            limit,
+            period: Period::Day,
+            warn_at: 0.8,
+        }
+    }
+
+    fn usage(tokens: u64) -> Usage {
+        Usage {
+            prompt_eval_count: tokens / 2,
+            eval_count: tokens - tokens / 2,
+            ..Usage::default()
+        }
+    }
+
+    fn counter(budgets: &Budgets, client: &str) -> (u64, u64, u64) {
+        let counters = budgets.counters.lock().unwrap();
+        let counter = &counters[&(0, client.to_string(), String::new())];
+        (counter.used, counter.requests, counter.reserved)
+    }
+
+    #[test]
+    fn requests_under_way_hold_the_average() {
+        let budgets = budgets(&[daily(Some(EACH), 100)]);
+        let now = days_from_civil(2024, 5, 10) * DAY + 12 * HOUR;
+
+        // Nothing to go by yet, so the first request holds nothing
+        let first = budgets.check_at("alice", None, now).ok().unwrap();
+        budgets.settle_at(first, "r1", "alice", Some(&usage(30)), now);
+        assert_eq!(counter(&budgets, "alice"), (30, 1, 0));
+
+        let held: Vec<Reservation> = (0..3).map(|_| budgets.check_at("alice", None, now).ok().unwrap()).collect();
+        assert_eq!(counter(&budgets, "alice"), (30, 1, 90));
+        let exhausted = budgets.check_at("alice", None, now).err().unwrap();
+        assert_eq!(exhausted.name, "100 tokens per day for each client");
+        assert_eq!(exhausted.resets_in, Duration::from_secs(12 * HOUR));
+
+        // Other clients have their own allowance
+        assert!(budgets.check_at("bob", None, now).is_ok());
+
+        let mut held = held.into_iter();
+        budgets.settle_at(held.next().unwrap(), "r2", "alice", None, now);
+        assert_eq!(counter(&budgets, "alice"), (30, 1, 60));
+        budgets.settle_at(held.next().unwrap(), "r3", "alice", Some(&usage(10)), now);
+        assert_eq!(counter(&budgets, "alice"), (40, 2, 30));
+        assert!(budgets.check_at("alice", None, now).is_ok());
+    }
+
+    #[test]
+    fn a_new_period_drops_old_reservations() {
+        let budgets = budgets(&[daily(None, 100)]);
+        let now = days_from_civil(2024, 5, 10) * DAY + 23 * HOUR;
+        let first = budgets.check_at("alice", None, now).ok().unwrap();
+        budgets.settle_at(first, "r1", "alice", Some(&usage(60)), now);
+        let held = budgets.check_at("alice", None, now).ok().unwrap();
+        assert!(budgets.check_at("alice", None, now).is_err());
+
+        // Settled after midnight, the request counts towards the new day
+        let tomorrow = now + 2 * HOUR;
+        budgets.settle_at(held, "r2", "alice", Some(&usage(20)), tomorrow);
+        let counters = budgets.counters.lock().unwrap();
+        let counter = &counters[&(0, String::new(), String::new())];
+        assert_eq!((counter.used, counter.requests, counter.reserved), (20, 1, 0));
+        assert_eq!(counter.period_start, Period::Day.start(tomorrow));
+    }
+
+    #[test]
+    fn a_named_budget_covers_only_its_client() {
+        let budgets = budgets(&[daily(Some("alice"), 10)]);
+        let now = days_from_civil(2024, 5, 10) * DAY;
+        let reservation = budgets.check_at("alice", None, now).ok().unwrap();
+        budgets.settle_at(reservation, "r1", "alice", Some(&usage(10)), now);
+        assert!(budgets.check_at("alice", None, now).is_err());
+        let reservation = budgets.check_at("bob", None, now).ok().unwrap();
+        assert!(reservation.holds.is_empty());
+    }
+
+    #[test]
+    fn warns_once_per_period() {
+        let budgets = budgets(&[daily(None, 100)]);
+        let now = days_from_civil(2024, 5, 10) * DAY;
+        let reservation = budgets.check_at("alice", None, now).ok().unwrap();
+        budgets.settle_at(reservation, "r1", "alice", Some(&usage(80)), now);
+        assert!(budgets.counters.lock().unwrap().values().all(|counter| counter.warned));
+        let reservation = budgets.check_at("alice", None, now + DAY).ok().unwrap();
+        assert!(budgets.counters.lock().unwrap().values().all(|counter| !counter.warned));
+        budgets.settle_at(reservation, "r2", "alice", None, now + DAY);
+    }
+}
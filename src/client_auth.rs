@@ -0,0 +1,143 @@
+//! Policy for client-supplied `Authorization` headers
+//!
+//! By default the proxy replaces whatever the client sent with its own
+//! credentials. Routes can instead let client credentials through, drop them,
+//! or translate a proxy key into a per-client remote key.
+
+use std::fmt;
+
+use clap::ValueEnum;
+use hyper::header::AUTHORIZATION;
+use hyper::HeaderMap;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::config::{ClientConfig, Config};
+use crate::keypool::mask_secret;
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientAuthPolicy {
+    /// Replace the client's header with the proxy's credentials
+    Inject,
+    /// Forward the client's header if it sent one, otherwise inject
+    PassthroughIfPresent,
+    /// Drop the client's header and send no credentials
+    Strip,
+    /// Translate a known client key into that client's remote key
+    Map,
+}
+
+impl fmt::Display for ClientAuthPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ClientAuthPolicy::Inject => "inject",
+            ClientAuthPolicy::PassthroughIfPresent => "passthrough-if-present",
+            ClientAuthPolicy::Strip => "strip",
+            ClientAuthPolicy::Map => "map",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What to send to the remote for one request
+pub enum Decision {
+    /// Add the proxy's own credentials
+    Inject,
+    /// Keep the client's `Authorization` header as is
+    Passthrough,
+    /// Send no `Authorization` header
+    Strip,
+    /// Send `Authorization: Bearer <key>`
+    Mapped(String),
+    /// Refuse the request with a 401
+    Reject(&'static str),
+}
+
+/// Short, non-reversible identifier for a secret, safe to log
+pub fn fingerprint(secret: &str) -> String {
+    let digest = Sha256::digest(secret.as_bytes());
+    format!("sha256:{}", &hex::encode(digest)[..12])
+}
+
+/// Extracts the token from an `Authorization: Bearer <token>` header
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// Finds the configured client whose key the request carries
+pub fn identify<'a>(headers: &HeaderMap, config: &'a Config) -> Option<&'a ClientConfig> {
+    bearer_token(headers).and_then(|token| config.client_by_key(token))
+}
+
+/// Applies the policy to the request headers, returning the decision and a
+/// description of it for the log
+pub fn decide(
+    policy: ClientAuthPolicy,
+    headers: &HeaderMap,
+    client: Option<&ClientConfig>,
+) -> (Decision, String) {
+    let presented = headers.get(AUTHORIZATION).map(|value| {
+        fingerprint(&String::from_utf8_lossy(value.as_bytes()))
+    });
+
+    match policy {
+        ClientAuthPolicy::Inject => {
+            let note = match presented {
+                Some(fp) => format!("replacing client credentials ({}) with proxy credentials", fp),
+                None => "adding proxy credentials".to_string(),
+            };
+            (Decision::Inject, note)
+        }
+        ClientAuthPolicy::PassthroughIfPresent => match presented {
+            Some(fp) => (
+                Decision::Passthrough,
+                format!("forwarding client credentials ({})", fp),
+            ),
+            None => (
+                Decision::Inject,
+                "no client credentials, adding proxy credentials".to_string(),
+            ),
+        },
+        ClientAuthPolicy::Strip => {
+            let note = match presented {
+                Some(fp) => format!("dropping client credentials ({})", fp),
+                None => "sending no credentials".to_string(),
+            };
+            (Decision::Strip, note)
+        }
+        ClientAuthPolicy::Map => match (client, presented) {
+            (Some(client), _) => match &client.upstream_key {
+                Some(key) => (
+                    Decision::Mapped(key.clone()),
+                    format!(
+                        "client '{}' mapped to remote key {}",
+                        client.name,
+                        mask_secret(key)
+                    ),
+                ),
+                None => (
+                    Decision::Inject,
+                    format!(
+                        "client '{}' has no remote key, adding proxy credentials",
+                        client.name
+                    ),
+                ),
+            },
+            (None, Some(fp)) => (
+                Decision::Reject("unknown client key"),
+                format!("rejecting unknown client credentials ({})", fp),
+            ),
+            (None, None) => (
+                Decision::Reject("missing client key"),
+                "rejecting request without client credentials".to_string(),
+            ),
+        },
+    }
+}
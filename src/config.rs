@@ -0,0 +1,153 @@
+//! Configuration file
+//!
+//! Command-line flags set the defaults for every request. The optional TOML
+//! file passed with `--config` adds per-route overrides, the list of known
+//! clients, per-model limits and token budgets:
+//!
+//! ```toml
+//! [[routes]]
+//! prefix = "/api/embed"
+//! client_auth = "strip"
+//!
+//! [[routes]]
+//! prefix = "/api/chat"
+//! profile = "team"
+//! rate_limit = { requests_per_second = 2, burst = 10 }
+//! timeouts = { idle = "2m", total = "30m" }
+//! max_attempts = 2
+//!
+//! [[clients]]
+//! name = "alice"
+//! key = "proxy-key-for-alice"
+//! upstream_key = "remote-key-for-alice"
+//!
+//! [[models]]
+//! name = "llama3.1:70b"
+//! max_concurrency = 2
+//! timeouts = { first_byte = "15m" }
+//!
+//! [[budgets]]
+//! client = "*"
+//! tokens = "completion"
+//! limit = 2000000
+//! period = "day"
+//! ```
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+
+use crate::budget::BudgetConfig;
+use crate::client_auth::ClientAuthPolicy;
+use crate::rate_limit::RateLimitConfig;
+use crate::scheduler::Priority;
+use crate::timeouts::TimeoutConfig;
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    #[serde(default)]
+    pub clients: Vec<ClientConfig>,
+    #[serde(default)]
+    pub models: Vec<ModelConfig>,
+    #[serde(default)]
+    pub budgets: Vec<BudgetConfig>,
+}
+
+/// Settings for requests whose path starts with `prefix`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub prefix: String,
+    /// What to do with a client-supplied `Authorization` header
+    pub client_auth: Option<ClientAuthPolicy>,
+    /// Stored credential profile to authenticate to the remote with
+    pub profile: Option<String>,
+    /// Requests per second allowed to each client on this route
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Priority class of requests on this route, ahead of the client's
+    pub priority: Option<Priority>,
+    /// Remote timeouts for requests on this route
+    pub timeouts: Option<TimeoutConfig>,
+    /// Times a request on this route is sent to the remote before a failure is relayed
+    pub max_attempts: Option<u32>,
+}
+
+/// A client known to the proxy, identified by the key it presents
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    pub name: String,
+    /// Key the client sends as `Authorization: Bearer <key>`
+    pub key: String,
+    /// Remote key to send on this client's behalf under the `map` policy
+    pub upstream_key: Option<String>,
+    /// Share of free slots this client gets while others are queued too [default: 1]
+    pub weight: Option<f64>,
+    /// Priority class of this client's requests [default: interactive]
+    pub priority: Option<Priority>,
+}
+
+/// Settings for requests naming a particular model
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ModelConfig {
+    pub name: String,
+    /// Requests for this model running on the remote at once
+    pub max_concurrency: Option<usize>,
+    /// Remote timeouts for requests for this model, ahead of the route's
+    pub timeouts: Option<TimeoutConfig>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let config: Config = toml::from_str(&contents)
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+
+        for client in &config.clients {
+            if client.key.is_empty() {
+                anyhow::bail!("Client '{}' has an empty key", client.name);
+            }
+            if client.weight.is_some_and(|weight| !(weight > 0.0 && weight.is_finite())) {
+                anyhow::bail!("Client '{}' must have a positive weight", client.name);
+            }
+        }
+        for route in &config.routes {
+            if route.max_attempts == Some(0) {
+                anyhow::bail!("Route '{}' must allow at least one attempt", route.prefix);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Finds the route with the longest prefix matching the request path
+    pub fn route(&self, path: &str) -> Option<&RouteConfig> {
+        self.routes
+            .iter()
+            .filter(|route| path.starts_with(&route.prefix))
+            .max_by_key(|route| route.prefix.len())
+    }
+
+    /// Finds the settings for a model
+    pub fn model(&self, name: &str) -> Option<&ModelConfig> {
+        self.models.iter().find(|model| model.name == name)
+    }
+
+    /// Finds the client presenting the given key. Every key is compared in
+    /// constant time, so response times don't give away how much of one matched.
+    pub fn client_by_key(&self, key: &str) -> Option<&ClientConfig> {
+        let mut found = None;
+        for client in &self.clients {
+            if bool::from(client.key.as_bytes().ct_eq(key.as_bytes())) {
+                found.get_or_insert(client);
+            }
+        }
+        found
+    }
+}
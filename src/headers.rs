@@ -0,0 +1,147 @@
+//! Header forwarding
+//!
+//! Copies headers between the client and the remote as an intermediary
+//! should (RFC 9110 section 7.6): hop-by-hop headers and every header named
+//! in `Connection` stay on their own connection, repeated headers keep all
+//! of their values, and `X-Forwarded-For`, `X-Forwarded-Proto` and `Via`
+//! record the hop through the proxy.
+
+use std::net::IpAddr;
+
+use clap::ValueEnum;
+use hyper::header::{HeaderName, HeaderValue, CONNECTION, HOST, VIA};
+use hyper::{HeaderMap, Version};
+
+/// Headers that only apply to a single connection and are never forwarded
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// Name the proxy uses for itself in `Via`
+const VIA_PSEUDONYM: &str = "ollama-agent";
+
+/// How to fill in `X-Forwarded-For`
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardedFor {
+    /// Append the client address to any chain the client sent
+    Append,
+    /// Drop any chain the client sent and send only the client address
+    Replace,
+    /// Forward whatever the client sent, without adding anything
+    Off,
+}
+
+/// Which forwarding headers the proxy adds
+#[derive(Debug, Clone)]
+pub struct ForwardingSettings {
+    pub forwarded_for: ForwardedFor,
+    pub forwarded_proto: bool,
+    pub via: bool,
+    /// Outer proxies whose `X-Forwarded-Proto` is passed on
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// Removes hop-by-hop headers, including any listed in `Connection`
+pub fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|token| HeaderName::from_bytes(token.trim().as_bytes()).ok())
+        .collect();
+
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+/// Builds the headers for the remote request from the client's headers.
+///
+/// `Host` is left for the HTTP client to set from the remote URL, and the
+/// client's `Authorization` is only kept when `keep_authorization` is set.
+pub fn forward_request_headers(client_headers: HeaderMap, keep_authorization: bool) -> HeaderMap {
+    let mut headers = client_headers;
+    remove_hop_by_hop(&mut headers);
+    headers.remove(HOST);
+    if !keep_authorization {
+        headers.remove(hyper::header::AUTHORIZATION);
+    }
+    headers
+}
+
+/// Adds `X-Forwarded-For`, `X-Forwarded-Proto` and `Via` to a remote request
+pub fn add_forwarding_headers(
+    headers: &mut HeaderMap,
+    client_ip: IpAddr,
+    version: Version,
+    settings: &ForwardingSettings,
+) {
+    let peer = client_ip;
+    let client_ip = HeaderValue::from_str(&client_ip.to_string()).expect("IP is a valid header");
+    match settings.forwarded_for {
+        ForwardedFor::Append => {
+            // Fold an existing chain into one value so the client address ends up last
+            let chain: Vec<&str> = headers
+                .get_all(X_FORWARDED_FOR)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect();
+            let value = if chain.is_empty() {
+                client_ip
+            } else {
+                let joined = format!("{}, {}", chain.join(", "), client_ip.to_str().unwrap_or(""));
+                HeaderValue::from_str(&joined).unwrap_or(client_ip)
+            };
+            headers.insert(X_FORWARDED_FOR, value);
+        }
+        ForwardedFor::Replace => {
+            headers.insert(X_FORWARDED_FOR, client_ip);
+        }
+        ForwardedFor::Off => {}
+    }
+
+    // Keep the scheme a trusted outer proxy reported; the proxy itself only listens on plain HTTP
+    let reported = headers.contains_key(X_FORWARDED_PROTO) && settings.trusted_proxies.contains(&peer);
+    if settings.forwarded_proto && !reported {
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("http"));
+    }
+
+    if settings.via {
+        append_via(headers, version);
+    }
+}
+
+/// Strips hop-by-hop headers from a remote response and adds `Via`
+pub fn forward_response_headers(headers: &mut HeaderMap, version: Version, settings: &ForwardingSettings) {
+    remove_hop_by_hop(headers);
+    if settings.via {
+        append_via(headers, version);
+    }
+}
+
+fn append_via(headers: &mut HeaderMap, version: Version) {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    let value = format!("{} {}", protocol, VIA_PSEUDONYM);
+    headers.append(VIA, HeaderValue::from_str(&value).expect("Via is a valid header"));
+}
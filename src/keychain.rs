@@ -1,7 +1,7 @@
 //! Keychain support for macOS
 //! 
 //! This module handles storing and retrieving API keys from the macOS Keychain,
-//! with support for different remote URLs.
+//! with support for different remote URLs and named profiles per URL.
 //! It is only compiled when the "keychain" feature is enabled.
 
 use anyhow::Result;
@@ -19,14 +19,38 @@ use std::hash::{Hash, Hasher};
 #[cfg(feature = "keychain")]
 const SERVICE_NAME: &str = "ollama-agent";
 
+// Account holding the list of saved URL/profile pairs, since the keychain can't be enumerated
+#[cfg(feature = "keychain")]
+const INDEX_ACCOUNT: &str = "index";
+
+/// Profile used when none is given; its entries keep the pre-profile account names
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// A saved key, identified by its remote URL and profile
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StoredKey {
+    pub remote_url: String,
+    pub profile: String,
+}
+
 /// Checks if the keychain feature is enabled
 pub fn is_keychain_enabled() -> bool {
     cfg!(feature = "keychain")
 }
 
-/// Helper function to create an account name based on the remote URL
+/// Helper function to create an account name based on the remote URL and profile
 #[cfg(feature = "keychain")]
-fn create_account_name(remote_url: &str) -> String {
+fn create_account_name(remote_url: &str, profile: &str) -> String {
+    let base = create_url_account_name(remote_url);
+    if profile == DEFAULT_PROFILE {
+        base
+    } else {
+        format!("{}#{}", base, profile)
+    }
+}
+
+#[cfg(feature = "keychain")]
+fn create_url_account_name(remote_url: &str) -> String {
     // Remove protocol and trailing slashes for cleaner account names
     let clean_url = remote_url
         .trim_start_matches("http://")
@@ -43,18 +67,58 @@ fn create_account_name(remote_url: &str) -> String {
     }
 }
 
+/// Reads the index of saved URL/profile pairs
+#[cfg(feature = "keychain")]
+fn read_index() -> Vec<StoredKey> {
+    let Ok(data) = get_generic_password(SERVICE_NAME, INDEX_ACCOUNT) else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&data)
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(url, profile)| StoredKey {
+            remote_url: url.to_string(),
+            profile: profile.to_string(),
+        })
+        .collect()
+}
+
+/// Adds or removes an entry in the index
+#[cfg(feature = "keychain")]
+fn update_index(entry: StoredKey, present: bool) -> Result<()> {
+    let mut index = read_index();
+    index.retain(|e| e != &entry);
+    if present {
+        index.push(entry);
+    }
+    index.sort();
+
+    let data: String = index
+        .iter()
+        .map(|e| format!("{}\t{}\n", e.remote_url, e.profile))
+        .collect();
+    let _ = delete_generic_password(SERVICE_NAME, INDEX_ACCOUNT);
+    set_generic_password(SERVICE_NAME, INDEX_ACCOUNT, data.as_bytes())
+        .map_err(|e| anyhow::anyhow!("Failed to update keychain index: {}", e))
+}
+
 /// Saves an API key to the macOS Keychain
 #[cfg(feature = "keychain")]
-pub fn save_api_key(api_key: &str, remote_url: &str) -> Result<()> {
+pub fn save_api_key(api_key: &str, remote_url: &str, profile: &str) -> Result<()> {
     use log::{debug, info};
-    debug!("Attempting to save API key for {} to macOS Keychain", remote_url);
+    debug!("Attempting to save API key for {} ({}) to macOS Keychain", remote_url, profile);
     
     // Check if the API key is empty
     if api_key.is_empty() {
         return Err(anyhow::anyhow!("Cannot save empty API key to keychain"));
     }
+
+    // Profile names end up in account names and the index
+    if profile.is_empty() || profile.contains(char::is_whitespace) {
+        return Err(anyhow::anyhow!("Invalid profile name '{}'", profile));
+    }
     
-    let account_name = create_account_name(remote_url);
+    let account_name = create_account_name(remote_url, profile);
     
     // First try to delete any existing password
     let _ = delete_generic_password(SERVICE_NAME, &account_name);
@@ -66,60 +130,78 @@ pub fn save_api_key(api_key: &str, remote_url: &str) -> Result<()> {
         api_key.as_bytes(),
     )
     .map_err(|e| anyhow::anyhow!("Failed to save API key to macOS Keychain: {}", e))?;
-    
-    info!("API key for {} successfully saved to macOS Keychain", remote_url);
+
+    update_index(
+        StoredKey {
+            remote_url: remote_url.to_string(),
+            profile: profile.to_string(),
+        },
+        true,
+    )?;
+    
+    info!("API key for {} ({}) successfully saved to macOS Keychain", remote_url, profile);
     Ok(())
 }
 
 /// Retrieves the API key from the macOS Keychain
 #[cfg(feature = "keychain")]
-pub fn get_api_key(remote_url: &str) -> Result<String> {
+pub fn get_api_key(remote_url: &str, profile: &str) -> Result<String> {
     use log::debug;
-    debug!("Attempting to read API key for {} from macOS Keychain", remote_url);
+    debug!("Attempting to read API key for {} ({}) from macOS Keychain", remote_url, profile);
     
-    let account_name = create_account_name(remote_url);
+    let account_name = create_account_name(remote_url, profile);
     
     let password = get_generic_password(
         SERVICE_NAME,
         &account_name,
     )
-    .map_err(|e| anyhow::anyhow!("Failed to retrieve API key from macOS Keychain for {}: {}", remote_url, e))?;
+    .map_err(|e| anyhow::anyhow!("Failed to retrieve API key from macOS Keychain for {} ({}): {}", remote_url, profile, e))?;
     
     // Convert password bytes to string
     let api_key = String::from_utf8(password.to_vec())
         .map_err(|e| anyhow::anyhow!("API key in keychain is not valid UTF-8: {}", e))?;
     
-    debug!("API key for {} retrieved from macOS Keychain (length: {})", remote_url, api_key.len());
+    debug!("API key for {} ({}) retrieved from macOS Keychain (length: {})", remote_url, profile, api_key.len());
     Ok(api_key)
 }
 
 /// Removes the API key from the macOS Keychain
 #[cfg(feature = "keychain")]
-pub fn delete_api_key(remote_url: &str) -> Result<()> {
+pub fn delete_api_key(remote_url: &str, profile: &str) -> Result<()> {
     use log::{debug, info};
-    debug!("Attempting to delete API key for {} from macOS Keychain", remote_url);
+    debug!("Attempting to delete API key for {} ({}) from macOS Keychain", remote_url, profile);
     
-    let account_name = create_account_name(remote_url);
+    let account_name = create_account_name(remote_url, profile);
     
     delete_generic_password(SERVICE_NAME, &account_name)
-        .map_err(|e| anyhow::anyhow!("Failed to delete API key from macOS Keychain for {}: {}", remote_url, e))?;
-    
-    info!("API key for {} successfully deleted from macOS Keychain", remote_url);
+        .map_err(|e| anyhow::anyhow!("Failed to delete API key from macOS Keychain for {} ({}): {}", remote_url, profile, e))?;
+
+    update_index(
+        StoredKey {
+            remote_url: remote_url.to_string(),
+            profile: profile.to_string(),
+        },
+        false,
+    )?;
+    
+    info!("API key for {} ({}) successfully deleted from macOS Keychain", remote_url, profile);
     Ok(())
 }
 
 /// Lists all Ollama API keys stored in the keychain
 /// 
 /// Since security-framework doesn't provide a direct way to list all items,
-/// we'll use a simpler approach by pre-populating a list of known URLs.
-/// Users will see these URLs in the list after they've used them at least once.
+/// saved keys are recorded in an index entry. Keys saved before the index
+/// existed are found by probing a list of commonly used URLs.
 #[cfg(feature = "keychain")]
-pub fn list_saved_urls() -> Result<Vec<String>> {
+pub fn list_saved_keys() -> Result<Vec<StoredKey>> {
     use log::{debug, info};
-    use std::collections::HashSet;
+    use std::collections::BTreeSet;
     
     debug!("Attempting to list all saved API keys from macOS Keychain");
 
+    let mut found: BTreeSet<StoredKey> = read_index().into_iter().collect();
+
     // Commonly used URLs to check
     let urls_to_check = vec![
         "api.ollama.ai",
@@ -127,41 +209,43 @@ pub fn list_saved_urls() -> Result<Vec<String>> {
         "127.0.0.1:11434",
     ];
     
-    // Use the predefined list of URLs to check
-    let urls_to_check = urls_to_check;
-    
     // Check each URL to see if we have an API key saved for it
-    let mut found_urls = HashSet::new();
     for url in urls_to_check {
-        let account_name = create_account_name(url);
+        let account_name = create_account_name(url, DEFAULT_PROFILE);
+        let already_indexed = found
+            .iter()
+            .any(|e| e.profile == DEFAULT_PROFILE && create_account_name(&e.remote_url, DEFAULT_PROFILE) == account_name);
         
         // Try to find a password for this account
-        if get_generic_password(SERVICE_NAME, &account_name).is_ok() {
-            found_urls.insert(url.to_string());
+        if !already_indexed && get_generic_password(SERVICE_NAME, &account_name).is_ok() {
+            found.insert(StoredKey {
+                remote_url: url.to_string(),
+                profile: DEFAULT_PROFILE.to_string(),
+            });
         }
     }
     
-    info!("Found {} saved API keys in macOS Keychain", found_urls.len());
-    Ok(found_urls.into_iter().collect())
+    info!("Found {} saved API keys in macOS Keychain", found.len());
+    Ok(found.into_iter().collect())
 }
 
 /// Dummy implementations for when the keychain feature is disabled
 #[cfg(not(feature = "keychain"))]
-pub fn save_api_key(_api_key: &str, _remote_url: &str) -> Result<()> {
+pub fn save_api_key(_api_key: &str, _remote_url: &str, _profile: &str) -> Result<()> {
     Err(anyhow::anyhow!("Keychain support is not enabled. Compile with '--features keychain' to enable this functionality."))
 }
 
 #[cfg(not(feature = "keychain"))]
-pub fn get_api_key(_remote_url: &str) -> Result<String> {
+pub fn get_api_key(_remote_url: &str, _profile: &str) -> Result<String> {
     Err(anyhow::anyhow!("Keychain support is not enabled. Compile with '--features keychain' to enable this functionality."))
 }
 
 #[cfg(not(feature = "keychain"))]
-pub fn delete_api_key(_remote_url: &str) -> Result<()> {
+pub fn delete_api_key(_remote_url: &str, _profile: &str) -> Result<()> {
     Err(anyhow::anyhow!("Keychain support is not enabled. Compile with '--features keychain' to enable this functionality."))
 }
 
 #[cfg(not(feature = "keychain"))]
-pub fn list_saved_urls() -> Result<Vec<String>> {
+pub fn list_saved_keys() -> Result<Vec<StoredKey>> {
     Err(anyhow::anyhow!("Keychain support is not enabled. Compile with '--features keychain' to enable this functionality."))
 }
\ No newline at end of file
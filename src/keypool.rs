@@ -0,0 +1,230 @@
+//! Pool of upstream API keys
+//!
+//! Keys are handed out round-robin. A key that the remote answers with
+//! `429 Too Many Requests` or `401 Unauthorized` is benched for a while so
+//! that the remaining keys absorb the traffic until it recovers.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use hyper::header::{HeaderMap, RETRY_AFTER};
+use hyper::StatusCode;
+use serde::Serialize;
+
+/// A single API key together with its usage counters
+struct PooledKey {
+    secret: String,
+    label: String,
+    benched_until: Mutex<Option<Instant>>,
+    requests: AtomicU64,
+    rate_limited: AtomicU64,
+    unauthorized: AtomicU64,
+}
+
+/// Round-robin pool of API keys for one upstream
+pub struct KeyPool {
+    keys: Vec<PooledKey>,
+    next: AtomicUsize,
+    rate_limit_bench: Duration,
+    unauthorized_bench: Duration,
+}
+
+/// The key picked for a request, passed back to [`KeyPool::report`]
+#[derive(Debug, Clone, Copy)]
+pub struct KeyLease {
+    index: usize,
+}
+
+/// Point-in-time counters for one key, safe to show to operators
+#[derive(Debug, Serialize)]
+pub struct KeyStats {
+    pub key: String,
+    pub requests: u64,
+    pub rate_limited: u64,
+    pub unauthorized: u64,
+    /// Seconds until the key is used again, if it is currently benched
+    pub benched_for_secs: Option<u64>,
+}
+
+/// Masks a secret down to its last four characters for logs and stats
+pub fn mask_secret(secret: &str) -> String {
+    let tail: String = secret
+        .chars()
+        .rev()
+        .take(4)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect();
+    format!("…{}", tail)
+}
+
+impl KeyPool {
+    pub fn new(keys: Vec<String>, rate_limit_bench: Duration, unauthorized_bench: Duration) -> Self {
+        let keys = keys
+            .into_iter()
+            .enumerate()
+            .map(|(i, secret)| PooledKey {
+                label: format!("key#{} ({})", i + 1, mask_secret(&secret)),
+                secret,
+                benched_until: Mutex::new(None),
+                requests: AtomicU64::new(0),
+                rate_limited: AtomicU64::new(0),
+                unauthorized: AtomicU64::new(0),
+            })
+            .collect();
+
+        Self {
+            keys,
+            next: AtomicUsize::new(0),
+            rate_limit_bench,
+            unauthorized_bench,
+        }
+    }
+
+    /// Picks the next key that is not benched.
+    ///
+    /// When every key is benched, returns how long until the first one
+    /// becomes available again.
+    pub fn acquire(&self) -> Result<KeyLease, Duration> {
+        let now = Instant::now();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut soonest: Option<Duration> = None;
+
+        for offset in 0..self.keys.len() {
+            let index = (start + offset) % self.keys.len();
+            let key = &self.keys[index];
+            let mut benched_until = key.benched_until.lock().unwrap();
+            match *benched_until {
+                Some(until) if until > now => {
+                    let wait = until - now;
+                    soonest = Some(soonest.map_or(wait, |s| s.min(wait)));
+                }
+                _ => {
+                    *benched_until = None;
+                    key.requests.fetch_add(1, Ordering::Relaxed);
+                    return Ok(KeyLease { index });
+                }
+            }
+        }
+
+        Err(soonest.unwrap_or_default())
+    }
+
+    pub fn secret(&self, lease: KeyLease) -> &str {
+        &self.keys[lease.index].secret
+    }
+
+    pub fn label(&self, lease: KeyLease) -> &str {
+        &self.keys[lease.index].label
+    }
+
+    /// Records the upstream status for a lease, benching the key if needed.
+    /// Returns how long the key was benched for.
+    pub fn report(&self, lease: KeyLease, status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+        let key = &self.keys[lease.index];
+        let bench_for = match status {
+            StatusCode::TOO_MANY_REQUESTS => {
+                key.rate_limited.fetch_add(1, Ordering::Relaxed);
+                parse_retry_after(headers).unwrap_or(self.rate_limit_bench)
+            }
+            StatusCode::UNAUTHORIZED => {
+                key.unauthorized.fetch_add(1, Ordering::Relaxed);
+                self.unauthorized_bench
+            }
+            _ => return None,
+        };
+
+        // The bench flags aren't capped like Retry-After, so fall back to the cap on overflow
+        let now = Instant::now();
+        let until = now.checked_add(bench_for).unwrap_or(now + MAX_RETRY_AFTER);
+        *key.benched_until.lock().unwrap() = Some(until);
+        Some(bench_for)
+    }
+
+    pub fn stats(&self) -> Vec<KeyStats> {
+        let now = Instant::now();
+        self.keys
+            .iter()
+            .map(|key| KeyStats {
+                key: key.label.clone(),
+                requests: key.requests.load(Ordering::Relaxed),
+                rate_limited: key.rate_limited.load(Ordering::Relaxed),
+                unauthorized: key.unauthorized.load(Ordering::Relaxed),
+                benched_for_secs: key
+                    .benched_until
+                    .lock()
+                    .unwrap()
+                    .filter(|until| *until > now)
+                    .map(|until| (until - now).as_secs().max(1)),
+            })
+            .collect()
+    }
+}
+
+/// Longest `Retry-After` taken from the remote; anything longer is cut down to this
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+
+/// Parses a `Retry-After` header given either as delta-seconds or an HTTP date,
+/// capped at an hour
+pub fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let wait = match value.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            date.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO)
+        }
+    };
+    Some(wait.min(MAX_RETRY_AFTER))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry_after(value: &str) -> Option<Duration> {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, value.parse().unwrap());
+        parse_retry_after(&headers)
+    }
+
+    #[test]
+    fn parses_seconds() {
+        assert_eq!(retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(" 0 "), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn caps_huge_values() {
+        assert_eq!(retry_after("18446744073709551615"), Some(MAX_RETRY_AFTER));
+        assert_eq!(retry_after("Fri, 31 Dec 9999 23:59:59 GMT"), Some(MAX_RETRY_AFTER));
+    }
+
+    #[test]
+    fn parses_http_dates() {
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(120));
+        let wait = retry_after(&date).unwrap();
+        assert!(wait > Duration::from_secs(110) && wait <= Duration::from_secs(120), "{wait:?}");
+        assert_eq!(retry_after("Sun, 06 Nov 1994 08:49:37 GMT"), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn ignores_garbage() {
+        for value in ["soon", "-5", "1.5", "", "18446744073709551616"] {
+            assert_eq!(retry_after(value), None, "{value}");
+        }
+        assert_eq!(parse_retry_after(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn huge_retry_after_benches_for_an_hour() {
+        let pool = KeyPool::new(vec!["secret".to_string()], Duration::from_secs(60), Duration::from_secs(60));
+        let lease = pool.acquire().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, "18446744073709551615".parse().unwrap());
+        assert_eq!(pool.report(lease, StatusCode::TOO_MANY_REQUESTS, &headers), Some(MAX_RETRY_AFTER));
+        assert!(pool.acquire().unwrap_err() > MAX_RETRY_AFTER - Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,325 @@
+//! The `keys` subcommand
+//!
+//! Manages the API keys stored in the macOS Keychain. Keys are read from the
+//! terminal without echo, or from stdin, so they never appear in shell
+//! history or `ps` output.
+//!
+//! `keys export` and `keys import` move every stored URL/profile/key entry
+//! between machines as a single age file encrypted with a passphrase. They
+//! only go through the public functions of the `keychain` module, so the
+//! bundle format does not depend on the credential store behind it.
+
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use age::secrecy::SecretString;
+use serde::{Deserialize, Serialize};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Subcommand;
+use hyper::{Body, Request, StatusCode};
+use log::info;
+
+use crate::auth::{AuthError, UpstreamAuth};
+use crate::keypool::{mask_secret, KeyPool};
+use crate::ollama_auth::{self, OllamaSigner};
+use crate::{body, keychain, HttpClient};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum KeysCommand {
+    /// Store a key for the remote URL, prompting for it without echo
+    Save {
+        /// Read the key from stdin instead of prompting
+        #[arg(long)]
+        stdin: bool,
+
+        /// Store the key without checking it against the remote first
+        #[arg(long)]
+        no_verify: bool,
+    },
+    /// List remote URLs and profiles with stored keys
+    List,
+    /// Delete the stored key for the remote URL and profile
+    Delete,
+    /// Write every stored key to a passphrase-encrypted bundle
+    Export {
+        /// Bundle file to write
+        #[arg(long)]
+        to: PathBuf,
+
+        /// Overwrite an existing bundle file without asking
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Store every key from a bundle written by `keys export`
+    Import {
+        /// Bundle file to read
+        #[arg(long)]
+        from: PathBuf,
+
+        /// Replace existing keys that differ without asking
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+// How long the remote gets to answer the call that checks a new key
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Environment variable that supplies the bundle passphrase for scripted use
+const PASSPHRASE_ENV: &str = "OLLAMA_AGENT_PASSPHRASE";
+
+const BUNDLE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Bundle {
+    version: u32,
+    entries: Vec<BundleEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundleEntry {
+    remote_url: String,
+    profile: String,
+    key: String,
+}
+
+pub async fn run(
+    command: &KeysCommand,
+    remote_url: &str,
+    profile: &str,
+    client: &HttpClient,
+) -> Result<()> {
+    if !keychain::is_keychain_enabled() {
+        bail!("Keychain support is not enabled. Compile with '--features keychain' to manage stored keys.");
+    }
+
+    match command {
+        KeysCommand::Save { stdin, no_verify } => {
+            let key = read_key(remote_url, *stdin)?;
+            if *no_verify {
+                info!("Skipping verification of the key against {}", remote_url);
+            } else {
+                verify_key(&key, remote_url, client).await?;
+            }
+            keychain::save_api_key(&key, remote_url, profile)?;
+            println!(
+                "Saved key {} for {} (profile: {})",
+                mask_secret(&key),
+                remote_url,
+                profile
+            );
+        }
+        KeysCommand::List => {
+            let saved = keychain::list_saved_keys()?;
+            if saved.is_empty() {
+                println!("No saved API keys found");
+            }
+            for (i, entry) in saved.iter().enumerate() {
+                println!("  {}. {} (profile: {})", i + 1, entry.remote_url, entry.profile);
+            }
+        }
+        KeysCommand::Delete => {
+            keychain::delete_api_key(remote_url, profile)?;
+            println!("Deleted key for {} (profile: {})", remote_url, profile);
+        }
+        KeysCommand::Export { to, yes } => export_bundle(to, *yes)?,
+        KeysCommand::Import { from, yes } => import_bundle(from, *yes)?,
+    }
+    Ok(())
+}
+
+fn export_bundle(path: &Path, assume_yes: bool) -> Result<()> {
+    if path.exists() && !confirm(&format!("{} exists, overwrite it?", path.display()), assume_yes)? {
+        bail!("Not overwriting {}", path.display());
+    }
+
+    let mut entries = Vec::new();
+    for stored in keychain::list_saved_keys()? {
+        let key = keychain::get_api_key(&stored.remote_url, &stored.profile)?;
+        entries.push(BundleEntry {
+            remote_url: stored.remote_url,
+            profile: stored.profile,
+            key,
+        });
+    }
+    if entries.is_empty() {
+        bail!("No stored keys to export");
+    }
+
+    let count = entries.len();
+    let passphrase = read_passphrase(true)?;
+    let plaintext = serde_json::to_vec(&Bundle {
+        version: BUNDLE_VERSION,
+        entries,
+    })?;
+    let ciphertext = age::encrypt(&age::scrypt::Recipient::new(passphrase), &plaintext)
+        .context("Failed to encrypt bundle")?;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    // The mode above only applies to a new file; tighten one being overwritten too
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))
+        .with_context(|| format!("Failed to restrict permissions on {}", path.display()))?;
+    file.write_all(&ciphertext)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    println!("Exported {} keys to {}", count, path.display());
+    Ok(())
+}
+
+fn import_bundle(path: &Path, assume_yes: bool) -> Result<()> {
+    let ciphertext =
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let passphrase = read_passphrase(false)?;
+    let plaintext = age::decrypt(&age::scrypt::Identity::new(passphrase), &ciphertext)
+        .map_err(|e| anyhow!("Failed to decrypt bundle (wrong passphrase?): {}", e))?;
+    let bundle: Bundle = serde_json::from_slice(&plaintext).context("Invalid bundle contents")?;
+    if bundle.version != BUNDLE_VERSION {
+        bail!("Unsupported bundle version {}", bundle.version);
+    }
+
+    let (mut imported, mut skipped) = (0, 0);
+    for entry in bundle.entries {
+        let label = format!("{} (profile: {})", entry.remote_url, entry.profile);
+        match keychain::get_api_key(&entry.remote_url, &entry.profile) {
+            Ok(existing) if existing == entry.key => {
+                println!("Unchanged: {}", label);
+                skipped += 1;
+                continue;
+            }
+            Ok(existing) => {
+                let question = format!(
+                    "Replace stored key {} for {} with {}?",
+                    mask_secret(&existing),
+                    label,
+                    mask_secret(&entry.key)
+                );
+                if !confirm(&question, assume_yes)? {
+                    println!("Kept existing key for {}", label);
+                    skipped += 1;
+                    continue;
+                }
+            }
+            Err(_) => {}
+        }
+        keychain::save_api_key(&entry.key, &entry.remote_url, &entry.profile)?;
+        println!("Imported: {}", label);
+        imported += 1;
+    }
+
+    println!("Imported {} keys, skipped {}", imported, skipped);
+    Ok(())
+}
+
+// Reads the bundle passphrase from the environment or the terminal
+fn read_passphrase(confirm_entry: bool) -> Result<SecretString> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        if passphrase.is_empty() {
+            bail!("{} is set but empty; the passphrase must not be empty", PASSPHRASE_ENV);
+        }
+        return Ok(SecretString::from(passphrase));
+    }
+    if !std::io::stdin().is_terminal() {
+        bail!("No terminal to prompt for the passphrase; set {}", PASSPHRASE_ENV);
+    }
+
+    let passphrase = rpassword::prompt_password("Bundle passphrase: ")?;
+    if passphrase.is_empty() {
+        bail!("The passphrase must not be empty");
+    }
+    if confirm_entry && rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+        bail!("Passphrases do not match");
+    }
+    Ok(SecretString::from(passphrase))
+}
+
+// Asks a yes/no question; without a terminal only --yes counts as an answer
+fn confirm(question: &str, assume_yes: bool) -> Result<bool> {
+    if assume_yes {
+        return Ok(true);
+    }
+    if !std::io::stdin().is_terminal() {
+        eprintln!("{} (pass --yes to confirm)", question);
+        return Ok(false);
+    }
+
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+// Reads the key from the terminal without echo, or from stdin when piped
+fn read_key(remote_url: &str, from_stdin: bool) -> Result<String> {
+    let key = if from_stdin || !std::io::stdin().is_terminal() {
+        let mut input = String::new();
+        std::io::stdin()
+            .read_to_string(&mut input)
+            .context("Failed to read key from stdin")?;
+        input
+    } else {
+        rpassword::prompt_password(format!("Key for {}: ", remote_url))
+            .context("Failed to read key from the terminal")?
+    };
+
+    let key = key.trim().to_string();
+    if key.is_empty() {
+        bail!("No key given");
+    }
+    Ok(key)
+}
+
+// Makes a cheap authenticated call so that a typo is caught before the key is stored
+async fn verify_key(key: &str, remote_url: &str, client: &HttpClient) -> Result<()> {
+    let auth = if ollama_auth::looks_like_private_key(key) {
+        UpstreamAuth::OllamaKey(OllamaSigner::from_openssh(key)?)
+    } else {
+        UpstreamAuth::Bearer(KeyPool::new(
+            vec![key.to_string()],
+            Default::default(),
+            Default::default(),
+        ))
+    };
+
+    let url = format!("{}/api/tags", remote_url.trim_end_matches('/'));
+    let req = Request::get(&url).body(body::boxed(Body::empty()))?;
+    let (req, _) = match auth.apply(req).await {
+        Ok(authorized) => authorized,
+        Err(AuthError::Failed(e)) => return Err(e),
+        Err(AuthError::Exhausted(_)) => bail!("The key is benched, so it can't be verified"),
+    };
+
+    info!("Verifying key against {}", url);
+    let resp = tokio::time::timeout(VERIFY_TIMEOUT, client.request(req))
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "{} did not answer within {}s (use --no-verify to store anyway)",
+                url,
+                VERIFY_TIMEOUT.as_secs()
+            )
+        })?
+        .with_context(|| format!("Failed to reach {} (use --no-verify to store anyway)", url))?;
+
+    match resp.status() {
+        status if status.is_success() => Ok(()),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(anyhow!(
+            "The remote rejected the key ({}); it was not saved",
+            resp.status()
+        )),
+        status => Err(anyhow!(
+            "Unexpected response from {}: {} (use --no-verify to store anyway)",
+            url,
+            status
+        )),
+    }
+}
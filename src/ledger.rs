@@ -0,0 +1,222 @@
+//! Usage ledger
+//!
+//! Keeps a row per request with token usage in a local SQLite database, for
+//! chargeback and for anything else that has to survive a restart. Rows are
+//! handed to a writer thread and committed in batches, so a request never
+//! waits on the disk. Rows older than the retention period are deleted by
+//! the same thread. On shutdown the thread commits what it has left before
+//! the proxy exits.
+
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use rusqlite::Connection;
+
+// Rows are committed once this many are waiting or the interval has passed
+const BATCH_SIZE: usize = 100;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS usage (
+        id INTEGER PRIMARY KEY,
+        timestamp_ms INTEGER NOT NULL,
+        request_id TEXT NOT NULL,
+        client TEXT NOT NULL,
+        model TEXT NOT NULL,
+        upstream TEXT NOT NULL,
+        status INTEGER NOT NULL,
+        prompt_tokens INTEGER NOT NULL,
+        completion_tokens INTEGER NOT NULL,
+        duration_ms INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS usage_timestamp ON usage (timestamp_ms);
+";
+
+/// One request's usage
+#[derive(Debug, Clone)]
+pub struct UsageRow {
+    /// When the request arrived, in milliseconds since the Unix epoch
+    pub timestamp_ms: i64,
+    pub request_id: String,
+    pub client: String,
+    pub model: String,
+    pub upstream: String,
+    pub status: u16,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub duration_ms: u64,
+}
+
+/// Opens the database at `path`, creating it and its tables if needed
+pub fn open(path: &Path) -> Result<Connection> {
+    let conn = Connection::open(path)
+        .with_context(|| format!("Failed to open usage database {}", path.display()))?;
+    // Let the report command read while the proxy writes
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.execute_batch(SCHEMA)
+        .with_context(|| format!("Failed to set up usage database {}", path.display()))?;
+    Ok(conn)
+}
+
+pub fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+/// Queues rows for the writer thread
+pub struct Ledger {
+    // Both taken by `close`
+    rows: Mutex<Option<Sender<UsageRow>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Ledger {
+    /// Opens the database and starts the writer; `retention_days` of 0 keeps rows forever
+    pub fn start(path: &Path, retention_days: u64) -> Result<Self> {
+        let conn = open(path)?;
+        let (rows, received) = mpsc::channel();
+        let writer = std::thread::Builder::new()
+            .name("usage-ledger".to_string())
+            .spawn(move || write_loop(conn, received, retention_days))
+            .context("Failed to start the usage ledger writer")?;
+        Ok(Ledger {
+            rows: Mutex::new(Some(rows)),
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    pub fn record(&self, row: UsageRow) {
+        // Rows from requests still finishing after `close` are lost
+        if let Some(rows) = &*self.rows.lock().unwrap() {
+            let _ = rows.send(row);
+        }
+    }
+
+    /// Closes the queue and waits for the writer to commit the rows still in it
+    pub fn close(&self) {
+        drop(self.rows.lock().unwrap().take());
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            if writer.join().is_err() {
+                error!("The usage ledger writer panicked; recent usage rows may be lost");
+            }
+        }
+    }
+}
+
+fn write_loop(mut conn: Connection, received: Receiver<UsageRow>, retention_days: u64) {
+    let mut batch = Vec::new();
+    let mut last_flush = Instant::now();
+    let mut last_prune: Option<Instant> = None;
+
+    loop {
+        let closed = match received.recv_timeout(FLUSH_INTERVAL) {
+            Ok(row) => {
+                batch.push(row);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        if !batch.is_empty()
+            && (closed || batch.len() >= BATCH_SIZE || last_flush.elapsed() >= FLUSH_INTERVAL)
+        {
+            match insert(&mut conn, &batch) {
+                Ok(()) => debug!("Wrote {} usage rows", batch.len()),
+                Err(e) => error!("Failed to write {} usage rows: {:#}", batch.len(), e),
+            }
+            batch.clear();
+            last_flush = Instant::now();
+        }
+
+        if retention_days > 0 && last_prune.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL) {
+            let cutoff = SystemTime::now() - Duration::from_secs(retention_days * 24 * 3600);
+            match conn.execute("DELETE FROM usage WHERE timestamp_ms < ?1", [unix_millis(cutoff)]) {
+                Ok(0) => {}
+                Ok(deleted) => info!("Deleted {} usage rows older than {} days", deleted, retention_days),
+                Err(e) => error!("Failed to delete old usage rows: {}", e),
+            }
+            last_prune = Some(Instant::now());
+        }
+
+        if closed {
+            return;
+        }
+    }
+}
+
+fn insert(conn: &mut Connection, rows: &[UsageRow]) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO usage (timestamp_ms, request_id, client, model, upstream, status,
+                                prompt_tokens, completion_tokens, duration_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        )?;
+        for row in rows {
+            stmt.execute(rusqlite::params![
+                row.timestamp_ms,
+                row.request_id,
+                row.client,
+                row.model,
+                row.upstream,
+                row.status,
+                row.prompt_tokens as i64,
+                row.completion_tokens as i64,
+                row.duration_ms as i64,
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn close_commits_pending_rows() {
+        let path = std::env::temp_dir().join(format!("ollama-agent-ledger-{}.db", std::process::id()));
+        let ledger = Ledger::start(&path, 0).unwrap();
+        for i in 0..3 {
+            ledger.record(UsageRow {
+                timestamp_ms: unix_millis(SystemTime::now()),
+                request_id: format!("request-{}", i),
+                client: "alice".to_string(),
+                model: "llama3".to_string(),
+                upstream: "http://remote".to_string(),
+                status: 200,
+                prompt_tokens: 10,
+                completion_tokens: 20,
+                duration_ms: 100,
+            });
+        }
+        // Well within the flush interval, so only closing can have written them
+        ledger.close();
+        ledger.record(UsageRow {
+            timestamp_ms: 0,
+            request_id: "late".to_string(),
+            client: String::new(),
+            model: String::new(),
+            upstream: String::new(),
+            status: 200,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            duration_ms: 0,
+        });
+
+        let conn = open(&path).unwrap();
+        let rows: i64 = conn.query_row("SELECT COUNT(*) FROM usage", [], |row| row.get(0)).unwrap();
+        assert_eq!(rows, 3);
+        drop(conn);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Adaptive concurrency limit
+//!
+//! With `--adaptive-concurrency`, the number of requests the scheduler lets
+//! run on the remote at once is found by AIMD rather than fixed: the limit
+//! grows by one for every round of requests that come back promptly while
+//! the limit is in use, and shrinks by a tenth when the remote answers with
+//! an error or takes `--latency-tolerance` times longer than usual to start
+//! responding. Requests over the limit wait in the scheduler's queue, where
+//! the queue length and timeout turn them away with a 503 long before they
+//! would have timed out on an overloaded remote.
+//!
+//! "Usual" is a baseline kept per model, since a small embedding model and a
+//! 70B chat model answer in very different times. It follows a faster
+//! response at once and a slower one only gradually, so it stays close to
+//! the latency of an unloaded remote.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use prometheus::Gauge;
+
+// Share of the limit kept after a sign of overload
+const BACKOFF: f64 = 0.9;
+
+// Where the limit starts, before anything is known about the remote
+const INITIAL_LIMIT: f64 = 4.0;
+
+// How far the baseline moves towards a slower sample
+const BASELINE_DRIFT: f64 = 0.01;
+
+// Latency within this of the baseline never counts as slow, however small the baseline
+const LATENCY_SLACK: Duration = Duration::from_millis(50);
+
+// Models past this many share one baseline
+const MAX_BASELINES: usize = 100;
+
+/// How one request fared on the remote
+pub struct Sample {
+    /// When the request was given its slot
+    pub admitted: Instant,
+    /// Time from being admitted to the remote's response headers
+    pub latency: Option<Duration>,
+    /// The remote answered with a server error or 429, or broke off the response
+    pub failed: bool,
+}
+
+pub struct AdaptiveLimit {
+    limit: f64,
+    min: f64,
+    max: f64,
+    tolerance: f64,
+    // Usual time to response headers by model, in seconds
+    baselines: HashMap<String, f64>,
+    last_decrease: Instant,
+    gauge: Gauge,
+}
+
+impl AdaptiveLimit {
+    pub fn new(min: usize, max: usize, tolerance: f64, gauge: Gauge) -> Self {
+        let (min, max) = (min.max(1) as f64, max.max(min.max(1)) as f64);
+        let limit = INITIAL_LIMIT.clamp(min, max);
+        gauge.set(limit);
+        AdaptiveLimit {
+            limit,
+            min,
+            max,
+            tolerance,
+            baselines: HashMap::new(),
+            last_decrease: Instant::now(),
+            gauge,
+        }
+    }
+
+    /// Requests allowed to run at once
+    pub fn limit(&self) -> usize {
+        self.limit as usize
+    }
+
+    /// Adjusts the limit for a finished request; `saturated` says whether the
+    /// limit was fully in use, as there is no point growing it otherwise
+    pub fn update(&mut self, model: &str, sample: &Sample, saturated: bool) {
+        let slow = match sample.latency {
+            Some(latency) if !sample.failed => self.is_slow(model, latency),
+            _ => false,
+        };
+        let previous = self.limit();
+
+        if sample.failed || slow {
+            // Requests admitted before the last decrease ran under the old
+            // limit, so their trouble has already been answered
+            if sample.admitted > self.last_decrease {
+                self.limit = (self.limit * BACKOFF).max(self.min);
+                self.last_decrease = Instant::now();
+            }
+        } else if saturated {
+            self.limit = (self.limit + 1.0 / self.limit).min(self.max);
+        }
+
+        if self.limit() != previous {
+            debug!(
+                "Concurrency limit {} -> {} ({})",
+                previous,
+                self.limit(),
+                if sample.failed {
+                    "remote error"
+                } else if slow {
+                    "slow response"
+                } else {
+                    "responses on time"
+                }
+            );
+        }
+        self.gauge.set(self.limit.floor());
+    }
+
+    fn is_slow(&mut self, model: &str, latency: Duration) -> bool {
+        let key = if self.baselines.len() < MAX_BASELINES || self.baselines.contains_key(model) {
+            model
+        } else {
+            ""
+        };
+        let secs = latency.as_secs_f64();
+        let baseline = self.baselines.entry(key.to_string()).or_insert(secs);
+        let slow = secs > *baseline * self.tolerance && secs - *baseline > LATENCY_SLACK.as_secs_f64();
+
+        // A remote that has become slower for good is caught up with eventually
+        if secs < *baseline {
+            *baseline = secs;
+        } else {
+            *baseline += (secs - *baseline) * BASELINE_DRIFT;
+        }
+        slow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(min: usize, max: usize) -> AdaptiveLimit {
+        AdaptiveLimit::new(min, max, 2.0, Gauge::new("limit", "Concurrency limit").unwrap())
+    }
+
+    // A request given its slot after the limit was last lowered
+    fn sample(latency_ms: u64, failed: bool) -> Sample {
+        Sample {
+            admitted: Instant::now() + Duration::from_millis(1),
+            latency: Some(Duration::from_millis(latency_ms)),
+            failed,
+        }
+    }
+
+    #[test]
+    fn grows_by_one_per_round_while_saturated() {
+        let mut limit = limiter(1, 100);
+        assert_eq!(limit.limit(), 4);
+        for _ in 0..4 {
+            limit.update("llama3", &sample(100, false), true);
+        }
+        assert_eq!(limit.limit(), 4);
+        limit.update("llama3", &sample(100, false), true);
+        assert_eq!(limit.limit(), 5);
+        assert_eq!(limit.gauge.get(), 5.0);
+
+        // Not growing a limit that isn't in use
+        for _ in 0..20 {
+            limit.update("llama3", &sample(100, false), false);
+        }
+        assert_eq!(limit.limit(), 5);
+    }
+
+    #[test]
+    fn shrinks_on_errors() {
+        let mut limit = limiter(1, 100);
+        limit.limit = 20.0;
+        limit.update("llama3", &sample(100, true), true);
+        assert_eq!(limit.limit, 18.0);
+
+        // Trouble from a request that started before the decrease was already answered
+        let early = Sample {
+            admitted: limit.last_decrease - Duration::from_millis(1),
+            latency: None,
+            failed: true,
+        };
+        limit.update("llama3", &early, true);
+        assert_eq!(limit.limit, 18.0);
+    }
+
+    #[test]
+    fn shrinks_on_slow_responses() {
+        let mut limit = limiter(1, 100);
+        limit.limit = 20.0;
+        limit.update("llama3", &sample(200, false), true);
+        let grown = limit.limit;
+        // Twice the baseline, but within the slack
+        limit.update("llama3", &sample(240, false), true);
+        assert!(limit.limit > grown);
+        limit.update("llama3", &sample(1000, false), true);
+        assert!(limit.limit < grown);
+
+        // Each model has its own baseline
+        let mut limit = limiter(1, 100);
+        limit.limit = 20.0;
+        limit.update("embed", &sample(10, false), true);
+        limit.update("llama3", &sample(1000, false), true);
+        assert!(limit.limit > 20.0);
+    }
+
+    #[test]
+    fn stays_within_bounds() {
+        let mut limit = limiter(2, 5);
+        for _ in 0..100 {
+            limit.update("llama3", &sample(100, false), true);
+        }
+        assert_eq!(limit.limit(), 5);
+        for _ in 0..100 {
+            limit.update("llama3", &sample(100, true), true);
+        }
+        assert_eq!(limit.limit(), 2);
+
+        // A maximum below the minimum is raised to it, and the start clamped
+        assert_eq!(limiter(8, 3).limit(), 8);
+        assert_eq!(limiter(0, 0).limit(), 1);
+    }
+}
@@ -1,30 +1,256 @@
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use hyper::client::HttpConnector;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Client, HeaderMap, Request, Response, Server, StatusCode};
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Client, Request, Response, Server, StatusCode};
 use hyper_tls::HttpsConnector;
 use log::{debug, error, info, warn};
 
+use access_log::{AccessLog, AccessRecord};
+use auth::{AuthError, AuthMode, UpstreamAuth};
+use budget::{Budgets, Reservation};
+use body::{BodyObserver, BoxError, InspectedBody, Outcome, ProxyBody};
+use client_auth::{ClientAuthPolicy, Decision};
+use config::Config;
+use headers::{ForwardedFor, ForwardingSettings};
+use keys::KeysCommand;
+use keypool::KeyPool;
+use ledger::{Ledger, UsageRow};
+use metrics::{Metrics, RequestLabels};
+use trace::{RequestTrace, ResponseTimes, Tracer};
+use usage::{UsageTap, UsageTotals};
+use ollama_auth::OllamaSigner;
+use rate_limit::{RateDecision, RateLimiter};
+use report::UsageCommand;
+use retry::{Failure, RetryPolicy, Skipped};
+use scheduler::{Admission, Permit, Priority, Rejected, Scheduler, SchedulerSettings, Ticket};
+use timeouts::{ClientDeadline, Deadline, Expiry, Phase, Timeouts};
+use sigv4::{PayloadSigning, SigV4Signer};
+
+mod access_log;
+mod admin;
+mod auth;
+mod body;
+mod budget;
+mod client_auth;
+mod config;
+mod headers;
 mod keychain;
+mod keys;
+mod keypool;
+mod ledger;
+mod limiter;
+mod metrics;
+mod ollama_auth;
+mod queue_progress;
+mod rate_limit;
+mod report;
+mod request_id;
+mod retry;
+mod scheduler;
+mod sigv4;
+mod timeouts;
+mod trace;
+mod usage;
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// TOML file with per-route settings and known clients
+    #[arg(short, long, env("OLLAMA_AGENT_CONFIG"))]
+    config: Option<std::path::PathBuf>,
+
     /// Local address to bind to
     #[arg(short, long, default_value = "127.0.0.1:11434")]
     local_addr: String,
 
     /// Remote Ollama API URL
-    #[arg(short, long, default_value = "https://api.ollama.ai")]
+    #[arg(short, long, global = true, default_value = "https://api.ollama.ai")]
     remote_url: String,
 
-    /// API key for authentication
-    #[arg(short, long, env("OLLAMA_API_KEY"))]
-    api_key: Option<String>,
+    /// Named credential profile to use from the macOS Keychain
+    #[arg(long, global = true, env("OLLAMA_AGENT_PROFILE"), default_value = keychain::DEFAULT_PROFILE)]
+    profile: String,
+
+    /// API key for authentication; repeat or comma-separate to rotate over several keys
+    #[arg(short, long, env("OLLAMA_API_KEY"), value_delimiter = ',')]
+    api_key: Vec<String>,
+
+    /// Seconds to bench a key after a 429 without a Retry-After header
+    #[arg(long, default_value = "60")]
+    key_rate_limit_bench: u64,
+
+    /// Seconds to bench a key after the remote rejects it with a 401
+    #[arg(long, default_value = "300")]
+    key_unauthorized_bench: u64,
+
+    /// What to do with a client-supplied Authorization header, unless a route overrides it
+    #[arg(long, value_enum, default_value = "inject")]
+    client_auth: ClientAuthPolicy,
+
+    /// How to authenticate to the remote
+    #[arg(long, value_enum, default_value = "bearer")]
+    auth_mode: AuthMode,
+
+    /// OpenSSH ed25519 key for --auth-mode ollama-key [default: the stored key for the remote URL, then ~/.ollama/id_ed25519]
+    #[arg(long)]
+    ollama_key: Option<std::path::PathBuf>,
+
+    /// AWS region for --auth-mode sigv4
+    #[arg(long, env("AWS_REGION"))]
+    aws_region: Option<String>,
+
+    /// AWS service name for --auth-mode sigv4
+    #[arg(long, default_value = "execute-api")]
+    aws_service: String,
+
+    /// Profile in the AWS credentials file [default: AWS_ACCESS_KEY_ID/AWS_SECRET_ACCESS_KEY, then AWS_PROFILE or "default"]
+    #[arg(long)]
+    aws_profile: Option<String>,
+
+    /// Whether to hash the buffered request body or send UNSIGNED-PAYLOAD
+    #[arg(long, value_enum, default_value = "buffered")]
+    aws_payload: PayloadSigning,
+
+    /// How to fill in X-Forwarded-For on remote requests
+    #[arg(long, value_enum, default_value = "append")]
+    x_forwarded_for: ForwardedFor,
+
+    /// Add X-Forwarded-Proto to remote requests
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    x_forwarded_proto: bool,
+
+    /// Address of an outer proxy whose X-Forwarded-Proto is kept (repeatable)
+    #[arg(long = "trusted-proxy", value_name = "IP")]
+    trusted_proxies: Vec<std::net::IpAddr>,
+
+    /// Add a Via header to remote requests and client responses
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    via: bool,
+
+    /// Write a JSON access log line per request to "stdout" or to this file
+    #[arg(long)]
+    access_log: Option<String>,
+
+    /// Size in megabytes at which the access log file is rotated
+    #[arg(long, default_value = "100")]
+    access_log_max_mb: u64,
+
+    /// Number of rotated access log files to keep
+    #[arg(long, default_value = "5")]
+    access_log_max_files: usize,
+
+    /// Access log fields to replace with "[redacted]"; repeat or comma-separate
+    #[arg(long, value_delimiter = ',')]
+    access_log_redact: Vec<String>,
+
+    /// Models that always get their own series in the metrics; repeat or comma-separate
+    #[arg(long, value_delimiter = ',')]
+    metrics_model: Vec<String>,
+
+    /// Other models that get their own series in the metrics once served, first come first served
+    #[arg(long, default_value = "20")]
+    metrics_max_models: usize,
+
+    /// OpenTelemetry collector to export traces to over OTLP/HTTP, e.g. http://localhost:4318
+    #[arg(long, env("OTEL_EXPORTER_OTLP_ENDPOINT"))]
+    otlp_endpoint: Option<String>,
+
+    /// Extra header for OTLP export requests, as name=value; repeat or comma-separate
+    #[arg(long, env("OTEL_EXPORTER_OTLP_HEADERS"), value_delimiter = ',')]
+    otlp_header: Vec<String>,
+
+    /// Service name reported in traces
+    #[arg(long, env("OTEL_SERVICE_NAME"), default_value = "ollama-agent")]
+    otel_service_name: String,
+
+    /// SQLite database to record per-request token usage in, and to read for `usage report`
+    #[arg(long, global = true, env("OLLAMA_AGENT_USAGE_DB"))]
+    usage_db: Option<std::path::PathBuf>,
+
+    /// Days to keep rows in the usage database; 0 keeps them forever
+    #[arg(long, default_value = "400")]
+    usage_retention_days: u64,
+
+    /// Chat, generate and embedding requests running on the remote at once; 0 for no limit
+    #[arg(long, default_value = "0")]
+    max_concurrency: usize,
+
+    /// Requests that can wait for a slot at once before new ones get a 503
+    #[arg(long, default_value = "100")]
+    max_queue: usize,
+
+    /// Seconds a request waits for a slot before it gets a 503
+    #[arg(long, default_value = "30")]
+    queue_timeout: u64,
+
+    /// While interactive and batch requests are both waiting, give every Nth slot to batch work; 0 never does
+    #[arg(long, default_value = "5")]
+    batch_every: usize,
+
+    /// Adjust the number of requests running on the remote to its latency and errors, up to --max-concurrency
+    #[arg(long)]
+    adaptive_concurrency: bool,
+
+    /// Lowest the adaptive concurrency limit goes
+    #[arg(long, default_value = "1")]
+    min_concurrency: usize,
+
+    /// Time to response headers over the usual by this factor counts as overload for the adaptive limit
+    #[arg(long, default_value = "2.0")]
+    latency_tolerance: f64,
+
+    /// Answer streaming chat and generate requests that have to wait at once, with X-Queue-Position and keep-alive whitespace
+    #[arg(long)]
+    queue_progress: bool,
+
+    /// Seconds to wait for a connection to the remote; 0 for no limit
+    #[arg(long, default_value = "10")]
+    connect_timeout: u64,
+
+    /// Seconds to wait for the remote to start responding; 0 for no limit
+    #[arg(long, default_value = "300")]
+    first_byte_timeout: u64,
+
+    /// Seconds a streamed response may go without a chunk before it is ended; 0 for no limit
+    #[arg(long, default_value = "120")]
+    idle_timeout: u64,
+
+    /// Seconds from sending a request to the remote to the end of its response; 0 for no limit
+    #[arg(long, default_value = "0")]
+    total_timeout: u64,
+
+    /// Times a request is sent to the remote before a connection error, 429, 502 or 503 is relayed
+    #[arg(long, default_value = "3")]
+    max_attempts: u32,
+
+    /// Milliseconds to wait before the first retry, doubled for each one after it
+    #[arg(long, default_value = "250")]
+    retry_backoff_ms: u64,
+
+    /// Longest wait in seconds before a retry; a longer Retry-After from the remote is relayed instead
+    #[arg(long, default_value = "10")]
+    retry_max_wait: u64,
+
+    /// Retries allowed on top of every request, across all requests, after a reserve of ten
+    #[arg(long, default_value = "0.2")]
+    retry_budget: f64,
+
+    /// Address for the admin endpoints (Prometheus metrics at /metrics, token usage at /usage, per-key usage at /keys); disabled if not set
+    #[arg(long)]
+    admin_addr: Option<String>,
 
     /// Save API key to macOS Keychain for the specified remote URL (requires keychain feature)
     #[arg(long)]
@@ -43,11 +269,82 @@ struct Args {
     list_keys: bool,
 }
 
-type HttpClient = Client<HttpsConnector<HttpConnector>>;
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Manage API keys stored in the macOS Keychain for the remote URL
+    Keys {
+        #[command(subcommand)]
+        action: KeysCommand,
+    },
+    /// Report on the token usage recorded with --usage-db
+    Usage {
+        #[command(subcommand)]
+        action: UsageCommand,
+    },
+}
+
+type HttpClient = Client<HttpsConnector<HttpConnector>, ProxyBody>;
 
 struct AppState {
-    client: HttpClient,
+    // Whether to read the start of request bodies to learn the model
+    inspect_bodies: bool,
+    // A client for each connect timeout in use
+    clients: HashMap<Option<Duration>, HttpClient>,
+    timeouts: Timeouts,
+    retries: RetryPolicy,
     args: Args,
+    config: Config,
+    auth: UpstreamAuth,
+    // Credentials of the profiles that routes select, by profile name
+    profile_auth: HashMap<String, UpstreamAuth>,
+    forwarding: ForwardingSettings,
+    access_log: Option<AccessLog>,
+    metrics: Metrics,
+    tracer: Tracer,
+    usage: UsageTotals,
+    ledger: Option<Ledger>,
+    budgets: Budgets,
+    rate_limiter: RateLimiter,
+    scheduler: Arc<Scheduler>,
+    // Host of the remote URL, as reported in logs
+    upstream: String,
+}
+
+impl AppState {
+    // Upstream credentials for a request, honouring the route's profile
+    fn upstream_auth(&self, route: Option<&config::RouteConfig>) -> &UpstreamAuth {
+        route
+            .and_then(|route| route.profile.as_ref())
+            .and_then(|profile| self.profile_auth.get(profile))
+            .unwrap_or(&self.auth)
+    }
+
+    // Timeouts for a request, the model's settings ahead of the route's ahead of the flags
+    fn timeouts(&self, route: Option<&config::RouteConfig>, model: Option<&str>) -> Timeouts {
+        let model = model.and_then(|model| self.config.model(model));
+        self.timeouts
+            .with(route.and_then(|route| route.timeouts.as_ref()))
+            .with(model.and_then(|model| model.timeouts.as_ref()))
+    }
+
+    // The client that connects with the given timeout
+    fn client(&self, connect_timeout: Option<Duration>) -> &HttpClient {
+        self.clients
+            .get(&connect_timeout)
+            .unwrap_or_else(|| &self.clients[&self.timeouts.connect])
+    }
+}
+
+// Helper function to build an Ollama-style JSON error response
+fn error_response(status: StatusCode, message: &str) -> Response<ProxyBody> {
+    let json = serde_json::json!({ "error": message }).to_string();
+    let mut response = Response::new(body::boxed(Body::from(json)));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    response
 }
 
 // Helper function to check if a request might be streaming
@@ -60,9 +357,597 @@ fn is_streaming_request(uri: &hyper::Uri) -> bool {
 async fn proxy_handler(
     req: Request<Body>,
     state: Arc<AppState>,
-) -> Result<Response<Body>, hyper::Error> {
+    client_addr: SocketAddr,
+) -> Result<Response<ProxyBody>, BoxError> {
+    let request_id = request_id::from_headers(req.headers());
+
+    // Queue lookups are answered by the proxy itself, to clients the remote would let in
+    if req.method() == hyper::Method::GET {
+        if let Some(id) = req.uri().path().strip_prefix(queue_progress::STATUS_PATH) {
+            let policy = state
+                .config
+                .route(req.uri().path())
+                .and_then(|route| route.client_auth)
+                .unwrap_or(state.args.client_auth);
+            let known_client = client_auth::identify(req.headers(), &state.config);
+            if let Decision::Reject(reason) = client_auth::decide(policy, req.headers(), known_client).0 {
+                warn!("[{}] Refusing queue lookup from {}: {}", request_id, client_addr.ip(), reason);
+                let mut response = error_response(StatusCode::UNAUTHORIZED, reason);
+                request_id::set(response.headers_mut(), &request_id);
+                return Ok(response);
+            }
+            return Ok(match state.scheduler.status(id) {
+                Some(status) => admin::json_response(&status).map(body::boxed),
+                None => error_response(
+                    StatusCode::NOT_FOUND,
+                    &format!("request {} is not waiting in the queue", id),
+                ),
+            });
+        }
+    }
+
+    let started = Instant::now();
+    let arrived = SystemTime::now();
+    let timestamp = humantime::format_rfc3339_millis(arrived).to_string();
+    let trace = RequestTrace::from_headers(req.headers(), started);
+
+    let (mut parts, body) = req.into_parts();
+    let known_client = client_auth::identify(&parts.headers, &state.config);
+    let client = known_client.map(|client| client.name.clone());
+    let (method, path) = (parts.method.to_string(), parts.uri.path().to_string());
+    let route = state.config.route(&path);
+    let configured_priority = route
+        .and_then(|route| route.priority)
+        .or(known_client.and_then(|client| client.priority));
+    let priority = Priority::for_request(configured_priority, &parts.headers);
+    // Meant for the proxy, not the remote
+    parts.headers.remove(scheduler::X_PRIORITY);
+    let deadline = ClientDeadline::take(&mut parts.headers, started.into());
+
+    // Refusals that only need the headers come before anything of the body is read
+    let client_key = client.clone().unwrap_or_else(|| client_addr.ip().to_string());
+    let rate = route.and_then(|route| state.rate_limiter.check(route, client.as_deref(), client_addr.ip()));
+    let over_rate_limit = rate.as_ref().is_some_and(|rate| !rate.allowed);
+    let policy = route
+        .and_then(|route| route.client_auth)
+        .unwrap_or(state.args.client_auth);
+    let (auth, auth_note) = client_auth::decide(policy, &parts.headers, known_client);
+    info!(
+        "[{}] Client auth ({}) for {} {}: {}",
+        request_id, policy, parts.method, parts.uri, auth_note
+    );
+    let unauthorized = match auth {
+        Decision::Reject(reason) => Some(reason),
+        _ => None,
+    };
+
+    // Read the start of the body when the model is needed before forwarding
+    let refused = over_rate_limit || unauthorized.is_some();
+    let inspected = if state.inspect_bodies && !refused && body::carries_model(&path) {
+        InspectedBody::read(body, body::INSPECT_LIMIT).await
+    } else {
+        Ok(InspectedBody::unread(body))
+    };
+    let model = inspected.as_ref().ok().and_then(|inspected| inspected.model());
+    let labels = state.metrics.request_started(&path, model.as_deref(), client.as_deref());
+
+    let mut ctx = RequestContext {
+        state: state.clone(),
+        request_id,
+        timestamp,
+        arrived,
+        started,
+        client_addr,
+        client_key,
+        method,
+        path,
+        model,
+        priority,
+        labels,
+        trace,
+        rate,
+        deadline,
+        auth,
+        bytes_in: Arc::new(AtomicU64::new(0)),
+        attempts: AtomicU32::new(0),
+        reservation: Reservation::default(),
+        stage: Stage::Queued,
+        answered: false,
+    };
+
+    if over_rate_limit {
+        warn!("[{}] Refusing request from {}: over the rate limit", ctx.request_id, ctx.client_key);
+        let response = error_response(StatusCode::TOO_MANY_REQUESTS, "rate limit exceeded; slow down");
+        return Ok(ctx.finish(response, None, None));
+    }
+    if let Some(reason) = unauthorized {
+        warn!("[{}] Refusing request from {}: {}", ctx.request_id, ctx.client_key, reason);
+        return Ok(ctx.finish(error_response(StatusCode::UNAUTHORIZED, reason), None, None));
+    }
+    let inspected = match inspected {
+        Ok(inspected) => inspected,
+        Err(err) => {
+            warn!("[{}] Failed to read request body: {}", ctx.request_id, err);
+            let response = error_response(StatusCode::BAD_REQUEST, "failed to read request body");
+            return Ok(ctx.finish(response, None, None));
+        }
+    };
+    if usage::reports_usage(&ctx.path) {
+        match state.budgets.check(&ctx.client_key, ctx.model.as_deref()) {
+            Ok(reservation) => ctx.reservation = reservation,
+            Err(exhausted) => {
+                warn!(
+                    "[{}] Refusing request from {}: budget '{}' is used up",
+                    ctx.request_id, ctx.client_key, exhausted.name
+                );
+                let resets_in = exhausted.resets_in.as_secs().max(1);
+                let mut response = error_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    &format!(
+                        "token budget '{}' is used up; it resets in {}",
+                        exhausted.name,
+                        humantime::format_duration(Duration::from_secs(resets_in))
+                    ),
+                );
+                response
+                    .headers_mut()
+                    .insert(hyper::header::RETRY_AFTER, hyper::header::HeaderValue::from(resets_in));
+                return Ok(ctx.finish(response, None, None));
+            }
+        }
+    }
+
+    let streams = inspected.streams();
+    let req = Request::from_parts(parts, inspected.into_body(&ctx.bytes_in));
+    if !(state.scheduler.is_enabled() && scheduler::is_scheduled(&ctx.path)) {
+        return ctx.forward(req, None, None).await;
+    }
+
+    // Wait for a slot if the remote is running as much as it may
+    let queue_started = Instant::now();
+    let model = ctx.model.clone().unwrap_or_default();
+    let deadline = ctx.deadline.map(|deadline| deadline.deadline.at);
+    let ticket = match state.scheduler.admit(priority, &ctx.client_key, &model, &ctx.request_id, deadline) {
+        Ok(Admission::Admitted(permit)) => {
+            return ctx.forward(req, Some(permit), Some(queue_started.elapsed())).await;
+        }
+        Ok(Admission::Queued(ticket)) => ticket,
+        Err(rejected) => {
+            let response = ctx.queue_rejection(rejected);
+            return Ok(ctx.finish(response, None, Some(queue_started.elapsed())));
+        }
+    };
+    if state.args.queue_progress && streams && usage::reports_usage(&ctx.path) {
+        return Ok(respond_while_queued(ctx, req, ticket, queue_started));
+    }
+    match ticket.wait().await {
+        Ok(permit) => ctx.forward(req, Some(permit), Some(queue_started.elapsed())).await,
+        Err(rejected) => {
+            let response = ctx.queue_rejection(rejected);
+            Ok(ctx.finish(response, None, Some(queue_started.elapsed())))
+        }
+    }
+}
+
+// Answers a streaming request that has to wait straight away, keeping the
+// connection open until it gets a slot and then relaying the remote's response
+fn respond_while_queued(
+    ctx: RequestContext,
+    req: Request<ProxyBody>,
+    ticket: Ticket,
+    queue_started: Instant,
+) -> Response<ProxyBody> {
+    let position = ticket.position();
+    info!("[{}] Queued at position {}, streaming progress", ctx.request_id, position);
+
+    let (mut sender, progress) = Body::channel();
+    let mut response = Response::new(body::boxed(progress));
+    let headers = response.headers_mut();
+    headers.insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/x-ndjson"),
+    );
+    headers.insert(queue_progress::X_QUEUE_POSITION, hyper::header::HeaderValue::from(position));
+    if let Some(rate) = &ctx.rate {
+        rate.set_headers(headers);
+    }
+    request_id::set(headers, &ctx.request_id);
+
+    tokio::spawn(async move {
+        let Some(admitted) = queue_progress::wait(ticket, &mut sender).await else {
+            return;
+        };
+        let queued = Some(queue_started.elapsed());
+        let request_id = ctx.request_id.clone();
+        let response = match admitted {
+            Ok(permit) => match ctx.forward(req, Some(permit), queued).await {
+                Ok(response) => response,
+                Err(err) => {
+                    error!("[{}] Failed to forward queued request: {}", request_id, err);
+                    sender.abort();
+                    return;
+                }
+            },
+            Err(rejected) => {
+                let response = ctx.queue_rejection(rejected);
+                ctx.finish(response, None, queued)
+            }
+        };
+        queue_progress::relay(response, sender).await;
+    });
+    response
+}
+
+// What is known about a request on its way through the proxy
+struct RequestContext {
+    state: Arc<AppState>,
+    request_id: String,
+    timestamp: String,
+    arrived: SystemTime,
+    started: Instant,
+    client_addr: SocketAddr,
+    // The client name, or the source address for unknown clients
+    client_key: String,
+    method: String,
+    path: String,
+    model: Option<String>,
+    priority: Priority,
+    labels: RequestLabels,
+    trace: RequestTrace,
+    rate: Option<RateDecision>,
+    // Set by the client with X-Request-Timeout or grpc-timeout
+    deadline: Option<ClientDeadline>,
+    // What to do with the client's own credentials
+    auth: Decision,
+    bytes_in: Arc<AtomicU64>,
+    // Times the request has been sent to the remote so far
+    attempts: AtomicU32,
+    // Tokens held under the budgets, handed on to the response's observer
+    reservation: Reservation,
+    // What the request is waiting on, should the client go away
+    stage: Stage,
+    // Whether a response was handed back, after which it is reported on once sent
+    answered: bool,
+}
+
+impl RequestContext {
+    // Sends the request to the remote, holding the scheduler slot it was given if any
+    async fn forward(
+        mut self,
+        req: Request<ProxyBody>,
+        mut permit: Option<Permit>,
+        queued: Option<Duration>,
+    ) -> Result<Response<ProxyBody>, BoxError> {
+        if let Some(waited) = queued {
+            self.state.metrics.observe_queue_wait(&self.labels, waited);
+        }
+        self.stage = Stage::Upstream;
+        let state = self.state.clone();
+        let timeouts = state.timeouts(state.config.route(&self.path), self.model.as_deref());
+        let response = forward_request(
+            req,
+            &state,
+            self.client_addr,
+            &self.request_id,
+            &mut self.trace,
+            &timeouts,
+            self.deadline.as_ref(),
+            &self.auth,
+            &self.attempts,
+        )
+        .await?;
+        if let Some(permit) = &mut permit {
+            permit.responded(response.status().as_u16());
+        }
+        if let (true, Some(model)) = (response.status().is_success(), &self.model) {
+            state.metrics.model_served(model);
+        }
+        Ok(self.finish(response, permit, queued))
+    }
+
+    // The 503, or 504 for a deadline, for a request the scheduler turned away
+    fn queue_rejection(&self, rejected: Rejected) -> Response<ProxyBody> {
+        let (status, message) = match rejected {
+            Rejected::QueueFull => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server busy: too many requests are waiting".to_string(),
+            ),
+            Rejected::TimedOut => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("server busy: no slot came free within {}s", self.state.args.queue_timeout),
+            ),
+            Rejected::Deadline => (
+                StatusCode::GATEWAY_TIMEOUT,
+                "server busy: the request's deadline would pass before a slot came free".to_string(),
+            ),
+        };
+        warn!("[{}] Refusing request from {}: {}", self.request_id, self.client_key, message);
+        self.state.metrics.queue_rejected(rejected.as_str());
+        error_response(status, &message)
+    }
+
+    // Adds the proxy's own headers and reports the request once the body has gone out
+    fn finish(
+        mut self,
+        mut response: Response<ProxyBody>,
+        permit: Option<Permit>,
+        queued: Option<Duration>,
+    ) -> Response<ProxyBody> {
+        if let Some(rate) = &self.rate {
+            rate.set_headers(response.headers_mut());
+        }
+        // Echo the ID on every response, including the proxy's own errors
+        request_id::set(response.headers_mut(), &self.request_id);
+        let expiry = response.extensions_mut().remove::<Expiry>();
+
+        // Metrics and the access log are recorded once the response body has gone out
+        let streamed = !response.headers().contains_key(hyper::header::CONTENT_LENGTH);
+        let mut observer = self.observer(response.status().as_u16(), streamed, permit, queued, expiry);
+        observer.record.attempts = self.attempts.load(Ordering::Relaxed);
+        self.answered = true;
+        let (parts, body) = response.into_parts();
+        Response::from_parts(parts, body::tap(body, observer))
+    }
+
+    // Reports on the request once its response is done with
+    fn observer(
+        &mut self,
+        status: u16,
+        streamed: bool,
+        permit: Option<Permit>,
+        queued: Option<Duration>,
+        expiry: Option<Expiry>,
+    ) -> ResponseObserver {
+        let record = AccessRecord {
+            timestamp: self.timestamp.clone(),
+            request_id: self.request_id.clone(),
+            client: self.client_key.clone(),
+            client_ip: self.client_addr.ip().to_string(),
+            method: self.method.clone(),
+            path: self.path.clone(),
+            model: self.model.clone(),
+            upstream: self.state.upstream.clone(),
+            status,
+            bytes_in: 0,
+            bytes_out: 0,
+            time_to_first_byte_ms: 0,
+            duration_ms: 0,
+            priority: self.priority.as_str(),
+            queued_ms: queued.map(|queued| queued.as_millis() as u64),
+            streamed,
+            attempts: 0,
+            prompt_tokens: None,
+            completion_tokens: None,
+            time_to_first_token_ms: None,
+            max_chunk_gap_ms: None,
+            tokens_per_second: None,
+            outcome: "",
+        };
+        ResponseObserver {
+            state: self.state.clone(),
+            usage: usage::reports_usage(&record.path).then(UsageTap::default),
+            record,
+            labels: self.labels.clone(),
+            trace: self.trace.clone(),
+            arrived: self.arrived,
+            started: self.started,
+            headers_sent: self.started.elapsed(),
+            first_byte: None,
+            last_chunk: None,
+            bytes_in: self.bytes_in.clone(),
+            reservation: std::mem::take(&mut self.reservation),
+            permit,
+            expiry,
+            stage: Stage::Streaming,
+        }
+    }
+}
+
+impl Drop for RequestContext {
+    // A request dropped before it was answered was given up on by its client.
+    // Any remote request goes with it, which closes its connection to the
+    // remote and so stops the generation there.
+    fn drop(&mut self) {
+        if self.answered {
+            return;
+        }
+        let mut observer = self.observer(CLIENT_CLOSED_REQUEST, false, None, None, None);
+        observer.stage = self.stage;
+        observer.record.attempts = self.attempts.load(Ordering::Relaxed);
+        observer.on_end(Outcome::Dropped);
+    }
+}
+
+// Status recorded for a request the client gave up on before it was answered, as nginx does
+const CLIENT_CLOSED_REQUEST: u16 = 499;
+
+// How far a request had got when its client went away
+#[derive(Debug, Clone, Copy)]
+enum Stage {
+    // Waiting for a slot, or not yet sent
+    Queued,
+    // Sent to the remote, waiting for its response to start
+    Upstream,
+    // Relaying the response
+    Streaming,
+}
+
+impl Stage {
+    fn as_str(self) -> &'static str {
+        match self {
+            Stage::Queued => "queued",
+            Stage::Upstream => "upstream",
+            Stage::Streaming => "streaming",
+        }
+    }
+}
+
+// Fills in the access log record as the response body goes out, then reports the request
+struct ResponseObserver {
+    state: Arc<AppState>,
+    record: AccessRecord,
+    labels: RequestLabels,
+    trace: RequestTrace,
+    usage: Option<UsageTap>,
+    arrived: SystemTime,
+    started: Instant,
+    headers_sent: Duration,
+    first_byte: Option<Duration>,
+    last_chunk: Option<Instant>,
+    bytes_in: Arc<AtomicU64>,
+    reservation: Reservation,
+    // The request's scheduler slot, held until the response is finished
+    permit: Option<Permit>,
+    // Set if the response was cut short by a timeout
+    expiry: Option<Expiry>,
+    stage: Stage,
+}
+
+impl BodyObserver for ResponseObserver {
+    fn on_chunk(&mut self, chunk: &Bytes) {
+        self.first_byte.get_or_insert_with(|| self.started.elapsed());
+        self.record.bytes_out += chunk.len() as u64;
+        let Some(usage) = self.usage.as_mut() else {
+            return;
+        };
+
+        // Chat and generate responses are timed chunk by chunk
+        let now = Instant::now();
+        let had_output = usage.output_seen();
+        usage.feed(chunk);
+        if !had_output && usage.output_seen() {
+            let elapsed = now - self.started;
+            self.record.time_to_first_token_ms = Some(elapsed.as_millis() as u64);
+            self.state.metrics.observe_first_token(&self.labels, elapsed);
+        }
+        if let (true, Some(last)) = (self.record.streamed, self.last_chunk) {
+            let gap = now - last;
+            let max_gap = self.record.max_chunk_gap_ms.get_or_insert(0);
+            *max_gap = (*max_gap).max(gap.as_millis() as u64);
+            self.state.metrics.observe_chunk_gap(&self.labels, gap);
+        }
+        self.last_chunk = Some(now);
+    }
+
+    fn on_end(&mut self, outcome: Outcome) {
+        let first_byte = self.first_byte.unwrap_or(self.headers_sent);
+        let duration = self.started.elapsed();
+        self.record.time_to_first_byte_ms = first_byte.as_millis() as u64;
+        self.record.duration_ms = duration.as_millis() as u64;
+        self.record.bytes_in = self.bytes_in.load(Ordering::Relaxed);
+        let expired = self.expiry.as_ref().and_then(Expiry::get);
+        self.record.outcome = match expired {
+            Some(_) => "timed_out",
+            None => outcome.as_str(),
+        };
+        // Let the next queued request in before the bookkeeping
+        if let Some(permit) = &mut self.permit {
+            if outcome == Outcome::Failed || expired.is_some() {
+                permit.failed();
+            }
+        }
+        self.permit = None;
+        if outcome == Outcome::Dropped {
+            let id = &self.record.request_id;
+            match self.stage {
+                Stage::Queued => info!("[{}] Client went away while queued", id),
+                Stage::Upstream => info!(
+                    "[{}] Client went away before the remote responded, cancelled the remote request",
+                    id
+                ),
+                Stage::Streaming => info!(
+                    "[{}] Client went away after {} bytes, closed the remote response",
+                    id, self.record.bytes_out
+                ),
+            }
+            self.state.metrics.request_cancelled(&self.labels, self.stage.as_str());
+        }
+        if let Some(phase) = expired {
+            warn!(
+                "[{}] Ended the response early: the {} timeout expired",
+                self.record.request_id,
+                phase.as_str()
+            );
+            self.state.metrics.upstream_timeout(phase);
+        }
+
+        self.state.metrics.request_finished(
+            &self.labels,
+            &metrics::Completed {
+                status: self.record.status,
+                bytes_in: self.record.bytes_in,
+                bytes_out: self.record.bytes_out,
+                first_byte,
+                duration,
+            },
+        );
+        let usage = self.usage.as_mut().and_then(UsageTap::finish);
+        let reservation = std::mem::take(&mut self.reservation);
+        self.state.budgets.settle(reservation, &self.record.request_id, &self.record.client, usage.as_ref());
+        if let Some(usage) = &usage {
+            self.record.prompt_tokens = Some(usage.prompt_eval_count);
+            self.record.completion_tokens = Some(usage.eval_count);
+            self.record.tokens_per_second = usage.tokens_per_second();
+            if let Some(tokens_per_second) = self.record.tokens_per_second {
+                self.state.metrics.observe_throughput(&self.labels, tokens_per_second);
+            }
+            let model = self.record.model.as_deref().unwrap_or("");
+            info!(
+                "[{}] Usage: {} prompt + {} completion tokens for {} ({})",
+                self.record.request_id,
+                usage.prompt_eval_count,
+                usage.eval_count,
+                self.record.client,
+                model
+            );
+            self.state.metrics.record_usage(&self.labels, usage);
+            // Keyed by the metric labels, which keep unknown clients and models from piling up
+            let model_label = self.state.metrics.model_label(self.record.model.as_deref());
+            self.state.usage.record(self.labels.client(), &model_label, &self.record.upstream, usage);
+            if let Some(ledger) = &self.state.ledger {
+                ledger.record(UsageRow {
+                    timestamp_ms: ledger::unix_millis(self.arrived),
+                    request_id: self.record.request_id.clone(),
+                    client: self.record.client.clone(),
+                    model: model.to_string(),
+                    upstream: self.record.upstream.clone(),
+                    status: self.record.status,
+                    prompt_tokens: usage.prompt_eval_count,
+                    completion_tokens: usage.eval_count,
+                    duration_ms: self.record.duration_ms,
+                });
+            }
+        }
+        self.state.tracer.finish(
+            &self.trace,
+            &self.record,
+            self.labels.endpoint(),
+            &ResponseTimes {
+                headers_sent: self.headers_sent,
+                first_byte: self.first_byte,
+                end: duration,
+            },
+            usage.as_ref(),
+        );
+        if let Some(access_log) = &self.state.access_log {
+            access_log.write(&self.record);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn forward_request(
+    req: Request<ProxyBody>,
+    state: &AppState,
+    client_addr: SocketAddr,
+    request_id: &str,
+    trace: &mut RequestTrace,
+    timeouts: &Timeouts,
+    client_deadline: Option<&ClientDeadline>,
+    auth: &Decision,
+    attempts: &AtomicU32,
+) -> Result<Response<ProxyBody>, BoxError> {
     let args = &state.args;
-    let client = &state.client;
 
     // Get the path and query from the request
     let uri = req.uri();
@@ -78,102 +963,350 @@ async fn proxy_handler(
     let (parts, body) = req.into_parts();
     let method_clone = parts.method.clone();
     let uri_clone = parts.uri.clone();
-    let mut builder = Request::builder()
-        .method(parts.method)
-        .uri(remote_url.clone());
-
-    // Add all the original headers
-    let mut headers = HeaderMap::new();
-    for (name, value) in parts.headers {
-        if let Some(name) = name {
-            // Skip host header as it will be set by the client
-            if name != hyper::header::HOST {
-                headers.insert(name, value);
-            }
-        }
+    let version = parts.version;
+
+    let route = state.config.route(uri_clone.path());
+    let upstream_auth = state.upstream_auth(route);
+
+    // Forward the end-to-end headers; client credentials only go through when the policy allows it
+    let mut headers =
+        headers::forward_request_headers(parts.headers, matches!(auth, Decision::Passthrough));
+    headers::add_forwarding_headers(&mut headers, client_addr.ip(), version, &state.forwarding);
+    request_id::set(&mut headers, request_id);
+    // The tighter of the client's deadline and the total timeout, with the time left passed on
+    let deadline = Deadline::earliest(
+        client_deadline.map(|client| client.deadline),
+        timeouts.total.map(|limit| Deadline::after(Phase::Total, limit)),
+    );
+    if let (Some(client), Some(deadline)) = (client_deadline, deadline) {
+        client.forward(deadline.at, &mut headers);
+    }
+    if state.tracer.is_enabled() {
+        headers.insert(trace::TRACEPARENT, trace.upstream_traceparent());
     }
 
-    // Add the API key header for authentication if provided
-    if let Some(api_key) = &args.api_key {
-        match format!("Bearer {}", api_key).parse() {
-            Ok(auth_value) => {
-                headers.insert("Authorization", auth_value);
-            }
-            Err(e) => {
-                error!("Failed to create Authorization header: {}", e);
-                // Continue without the header, the remote server will reject the request
-            }
+    // A small body of known length is held so it can be sent again, a streamed one only once
+    let max_attempts = state.retries.max_attempts(route.and_then(|route| route.max_attempts));
+    let (mut body, replay) = match HttpBody::size_hint(&body).exact() {
+        Some(size) if max_attempts > 1 && size <= body::INSPECT_LIMIT as u64 => {
+            (None, Some(hyper::body::to_bytes(body).await?))
         }
-    }
+        _ => (Some(body), None),
+    };
+    state.retries.request_started();
 
-    *builder.headers_mut().unwrap() = headers;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let body = match &replay {
+            Some(bytes) => body::boxed(Body::from(bytes.clone())),
+            None => body.take().unwrap_or_default(),
+        };
+        let mut builder = Request::builder()
+            .method(method_clone.clone())
+            .uri(remote_url.clone());
+        *builder.headers_mut().unwrap() = headers.clone();
 
-    // Log the outgoing request (excluding sensitive headers)
-    info!(
-        "Proxying request: {} {} -> {} {}",
-        method_clone,
-        uri_clone,
-        remote_url,
-        if is_stream { "[STREAMING]" } else { "" }
-    );
+        // Log the outgoing request (excluding sensitive headers)
+        info!(
+            "[{}] Proxying request: {} {} -> {} {}{}",
+            request_id,
+            method_clone,
+            uri_clone,
+            remote_url,
+            if is_stream { "[STREAMING]" } else { "" },
+            if attempt > 1 {
+                format!(" (attempt {} of {})", attempt, max_attempts)
+            } else {
+                String::new()
+            }
+        );
 
-    // Build and send the request to the remote server
-    let remote_req = match builder.body(body) {
-        Ok(req) => req,
-        Err(err) => {
-            error!("Failed to build remote request: {}", err);
-            let mut response = Response::new(Body::from("Internal Server Error"));
-            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        // Build and send the request to the remote server
+        let remote_req = match builder.body(body) {
+            Ok(req) => req,
+            Err(err) => {
+                error!("[{}] Failed to build remote request: {}", request_id, err);
+                let mut response = Response::new(body::boxed(Body::from("Internal Server Error")));
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                return Ok(response);
+            }
+        };
+
+        // Add credentials for the remote, afresh for every attempt so a retry can use another key
+        let authorized = match auth {
+            Decision::Inject => upstream_auth.apply(remote_req).await,
+            Decision::Mapped(key) => {
+                let mut remote_req = remote_req;
+                match format!("Bearer {}", key).parse() {
+                    Ok(auth_value) => {
+                        remote_req
+                            .headers_mut()
+                            .insert(hyper::header::AUTHORIZATION, auth_value);
+                        Ok((remote_req, None))
+                    }
+                    Err(e) => Err(AuthError::Failed(anyhow::anyhow!("Invalid mapped key: {}", e))),
+                }
+            }
+            // Rejected requests are answered before they get this far
+            Decision::Passthrough | Decision::Strip | Decision::Reject(_) => Ok((remote_req, None)),
+        };
+        let (remote_req, lease) = match authorized {
+            Ok(authorized) => authorized,
+            Err(AuthError::Exhausted(wait)) => {
+                // Every key is benched, don't hammer the remote with them
+                let retry_after = wait.as_secs().max(1);
+                warn!(
+                    "[{}] All API keys are benched, rejecting request for {}s",
+                    request_id, retry_after
+                );
+                let mut response = error_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "all upstream API keys are rate limited, retry later",
+                );
+                response
+                    .headers_mut()
+                    .insert(hyper::header::RETRY_AFTER, retry_after.into());
+                return Ok(response);
+            }
+            Err(AuthError::Failed(err)) => {
+                error!("[{}] Failed to authenticate remote request: {:#}", request_id, err);
+                let mut response = Response::new(body::boxed(Body::from("Internal Server Error")));
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                return Ok(response);
+            }
+        };
+        if let (Some(pool), Some(lease)) = (upstream_auth.key_pool(), lease) {
+            debug!("[{}] Using {} for {}", request_id, pool.label(lease), uri_clone);
+        }
+
+        // No point sending a request whose deadline has already passed
+        if let Some(deadline) = deadline.filter(|deadline| deadline.at <= tokio::time::Instant::now()) {
+            let response = upstream_timed_out(state, request_id, trace, deadline.phase, deadline.limit);
             return Ok(response);
         }
-    };
 
-    // Send the request to the remote server with a timeout
-    match tokio::time::timeout(
-        std::time::Duration::from_secs(300), // Increase timeout for streaming responses
-        client.request(remote_req),
-    )
-    .await
-    {
-        Ok(Ok(resp)) => {
-            let status = resp.status();
-            let content_type = resp
-                .headers()
-                .get(hyper::header::CONTENT_TYPE)
-                .and_then(|v| v.to_str().ok())
-                .unwrap_or("");
+        // Send the request to the remote server, giving up at whichever timeout comes first
+        trace.upstream_sent();
+        let give_up = Deadline::earliest(
+            timeouts.first_byte.map(|limit| Deadline::after(Phase::FirstByte, limit)),
+            deadline,
+        );
+        attempts.store(attempt, Ordering::Relaxed);
+        let sent = state.client(timeouts.connect).request(remote_req);
+        let result = match give_up {
+            Some(give_up) => tokio::time::timeout_at(give_up.at, sent)
+                .await
+                .map_err(|_| (give_up.phase, give_up.limit)),
+            None => Ok(sent.await),
+        };
+        let (response, failure) = match result {
+            Ok(Ok(mut resp)) => {
+                let status = resp.status();
+                trace.upstream_responded(status.as_u16());
+                let content_type = resp
+                    .headers()
+                    .get(hyper::header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("");
 
-            info!(
-                "Received response: {} {} (Content-Type: {})",
-                status.as_u16(),
-                status.canonical_reason().unwrap_or("Unknown"),
-                content_type
-            );
+                info!(
+                    "[{}] Received response: {} {} (Content-Type: {})",
+                    request_id,
+                    status.as_u16(),
+                    status.canonical_reason().unwrap_or("Unknown"),
+                    content_type
+                );
+
+                // Bench the key if the remote rejected or throttled it
+                if let Some((label, bench_for)) = upstream_auth.report(lease, status, resp.headers()) {
+                    warn!(
+                        "[{}] Upstream answered {} for {}, benching it for {}s",
+                        request_id,
+                        status.as_u16(),
+                        label,
+                        bench_for.as_secs()
+                    );
+                }
+
+                // Debug log for streaming responses
+                if content_type.contains("stream") || content_type.contains("event-stream") {
+                    info!("[{}] Detected streaming response, preserving chunked encoding", request_id);
+                }
 
-            // Debug log for streaming responses
-            if content_type.contains("stream") || content_type.contains("event-stream") {
-                info!("Detected streaming response, preserving chunked encoding");
+                let retry_after = keypool::parse_retry_after(resp.headers());
+                let failure = Failure::from_status(status, retry_after).map(|failure| (failure, retry_after));
+
+                // Drop the remote's connection-level headers before relaying the response
+                let remote_version = resp.version();
+                headers::forward_response_headers(resp.headers_mut(), remote_version, &state.forwarding);
+
+                (timeouts::limit_body(resp, timeouts.idle, deadline), failure)
+            }
+            Ok(Err(err)) if err.is_connect() && timeouts::is_connect_timeout(&err) => {
+                let limit = timeouts.connect.unwrap_or_default();
+                state.metrics.upstream_connect_error();
+                let response = upstream_timed_out(state, request_id, trace, Phase::Connect, limit);
+                (response, Some((Failure::ConnectTimeout, None)))
+            }
+            Ok(Err(err)) => {
+                // Return a 502 Bad Gateway error if the proxy request fails
+                error!("[{}] Proxy request failed: {}", request_id, err);
+                trace.upstream_failed(err.to_string());
+                // Nothing reached the remote if it couldn't be connected to, so it is safe to try again
+                let failure = err.is_connect().then_some((Failure::Connect, None));
+                if err.is_connect() {
+                    state.metrics.upstream_connect_error();
+                }
+                let mut response = Response::new(body::boxed(Body::from("Bad Gateway")));
+                *response.status_mut() = StatusCode::BAD_GATEWAY;
+                (response, failure)
+            }
+            Err((phase, limit)) => (upstream_timed_out(state, request_id, trace, phase, limit), None),
+        };
+
+        let Some((failure, retry_after)) = failure else {
+            return Ok(response);
+        };
+        let wait = if replay.is_none() {
+            Err(Skipped::StreamedBody)
+        } else if attempt >= max_attempts {
+            Err(Skipped::Attempts)
+        } else {
+            state.retries.wait(attempt, retry_after)
+        };
+        let wait = wait.and_then(|wait| match deadline {
+            Some(deadline) if tokio::time::Instant::now() + wait >= deadline.at => Err(Skipped::Deadline),
+            _ => Ok(wait),
+        });
+        match wait {
+            Ok(wait) => {
+                warn!(
+                    "[{}] Attempt {} of {} failed ({}), retrying in {}ms",
+                    request_id,
+                    attempt,
+                    max_attempts,
+                    failure.as_str(),
+                    wait.as_millis()
+                );
+                state.metrics.upstream_retry(failure);
+                // The failed response goes before the wait, freeing its connection
+                drop(response);
+                tokio::time::sleep(wait).await;
             }
+            Err(skipped) => {
+                // Requests that only ever get one attempt have nothing to report
+                if max_attempts > 1 {
+                    warn!(
+                        "[{}] Not retrying ({}): {}",
+                        request_id,
+                        failure.as_str(),
+                        skipped.message(attempt)
+                    );
+                    state.metrics.retry_skipped(skipped);
+                }
+                return Ok(response);
+            }
+        }
+    }
+}
+
+// The 504 for a remote request that ran out of time before the response started
+fn upstream_timed_out(
+    state: &AppState,
+    request_id: &str,
+    trace: &mut RequestTrace,
+    phase: Phase,
+    limit: Duration,
+) -> Response<ProxyBody> {
+    let message = phase.message(limit);
+    error!("[{}] Proxy request timed out: {}", request_id, message);
+    trace.upstream_failed(format!("{} timeout", phase.as_str()));
+    state.metrics.upstream_timeout(phase);
+    error_response(StatusCode::GATEWAY_TIMEOUT, &message)
+}
 
-            Ok(resp)
+// Builds the upstream authentication for the given keys and ed25519 key file
+fn build_upstream_auth(args: &Args, keys: &[String], key_file: Option<&Path>) -> Result<UpstreamAuth> {
+    match args.auth_mode {
+        AuthMode::Bearer => {
+            if keys.is_empty() {
+                return Ok(UpstreamAuth::None);
+            }
+            if keys.iter().any(|k| ollama_auth::looks_like_private_key(k)) {
+                warn!("The API key looks like an OpenSSH private key, did you mean --auth-mode ollama-key?");
+            }
+            Ok(UpstreamAuth::Bearer(KeyPool::new(
+                keys.to_vec(),
+                std::time::Duration::from_secs(args.key_rate_limit_bench),
+                std::time::Duration::from_secs(args.key_unauthorized_bench),
+            )))
         }
-        Ok(Err(err)) => {
-            // Return a 502 Bad Gateway error if the proxy request fails
-            error!("Proxy request failed: {}", err);
-            let mut response = Response::new(Body::from("Bad Gateway"));
-            *response.status_mut() = StatusCode::BAD_GATEWAY;
-            Ok(response)
+        AuthMode::OllamaKey => {
+            // Prefer an explicit key file, then a key from the credential store, then the Ollama default
+            let signer = if let Some(path) = key_file {
+                OllamaSigner::from_file(path)?
+            } else if let Some(pem) = keys.iter().find(|k| ollama_auth::looks_like_private_key(k))
+            {
+                OllamaSigner::from_openssh(pem).context("Invalid stored Ollama key")?
+            } else {
+                let path = ollama_auth::default_key_path()
+                    .context("Cannot locate ~/.ollama/id_ed25519, pass --ollama-key")?;
+                OllamaSigner::from_file(&path)?
+            };
+            Ok(UpstreamAuth::OllamaKey(signer))
         }
-        Err(_) => {
-            // Return a 504 Gateway Timeout error if the request times out
-            error!("Proxy request timed out");
-            let mut response = Response::new(Body::from("Gateway Timeout"));
-            *response.status_mut() = StatusCode::GATEWAY_TIMEOUT;
-            Ok(response)
+        AuthMode::Sigv4 => {
+            let region = args
+                .aws_region
+                .clone()
+                .context("--auth-mode sigv4 requires --aws-region or AWS_REGION")?;
+            let credentials = sigv4::Credentials::load(args.aws_profile.as_deref())?;
+            Ok(UpstreamAuth::SigV4(SigV4Signer::new(
+                credentials,
+                region,
+                args.aws_service.clone(),
+                args.aws_payload,
+            )))
         }
     }
 }
 
+// The timeouts set by the command-line flags
+fn default_timeouts(args: &Args) -> Timeouts {
+    Timeouts::from_secs(
+        args.connect_timeout,
+        args.first_byte_timeout,
+        args.idle_timeout,
+        args.total_timeout,
+    )
+}
+
+// Reads the keys stored for a remote URL and profile; several keys are stored comma-separated
+fn load_stored_keys(remote_url: &str, profile: &str) -> Result<Vec<String>> {
+    let stored = keychain::get_api_key(remote_url, profile)?;
+    Ok(stored
+        .split(',')
+        .map(|k| k.trim().to_string())
+        .filter(|k| !k.is_empty())
+        .collect())
+}
+
+// Creates the HTTPS client with timeouts suitable for streaming
+fn build_client(connect_timeout: Option<Duration>) -> HttpClient {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_connect_timeout(connect_timeout);
+    let https = HttpsConnector::new_with_connector(http);
+    Client::builder()
+        .pool_idle_timeout(std::time::Duration::from_secs(300))
+        .pool_max_idle_per_host(32) // Increase connection pool size
+        .http2_only(false) // Support both HTTP/1.1 and HTTP/2
+        .http2_initial_stream_window_size(1024 * 1024) // 1MB
+        .http2_initial_connection_window_size(1024 * 1024) // 1MB
+        .build::<_, ProxyBody>(https)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize logger
@@ -182,18 +1315,28 @@ async fn main() -> Result<()> {
     // Parse command-line arguments
     let mut args = Args::parse();
 
+    // Subcommands run instead of the proxy
+    match &args.command {
+        Some(Command::Keys { action }) => {
+            let client = build_client(default_timeouts(&args).connect);
+            return keys::run(action, &args.remote_url, &args.profile, &client).await;
+        }
+        Some(Command::Usage { action }) => return report::run(action, args.usage_db.as_deref()),
+        None => {}
+    }
+
     // Check if keychain feature is enabled
     if (args.save_key || args.delete_key || args.use_keychain) && !keychain::is_keychain_enabled() {
         warn!("macOS Keychain operations requested but keychain feature is not enabled");
         warn!("Compile with '--features keychain' to enable keychain integration");
 
         // If save-key was requested but not available, warn the user their key won't be saved
-        if args.save_key && args.api_key.is_some() {
+        if args.save_key && !args.api_key.is_empty() {
             warn!("API key will NOT be saved to keychain due to missing feature");
         }
 
         // If use-keychain was requested but not available, warn the user
-        if args.use_keychain && args.api_key.is_none() {
+        if args.use_keychain && args.api_key.is_empty() {
             warn!("Unable to retrieve API key from keychain due to missing feature");
         }
     }
@@ -202,14 +1345,14 @@ async fn main() -> Result<()> {
     if keychain::is_keychain_enabled() {
         // List saved keys if requested
         if args.list_keys {
-            match keychain::list_saved_urls() {
-                Ok(urls) => {
-                    if urls.is_empty() {
+            match keychain::list_saved_keys() {
+                Ok(saved) => {
+                    if saved.is_empty() {
                         info!("No saved API keys found in macOS Keychain");
                     } else {
                         info!("Saved API keys found for the following remote URLs:");
-                        for (i, url) in urls.iter().enumerate() {
-                            println!("  {}. {}", i + 1, url);
+                        for (i, entry) in saved.iter().enumerate() {
+                            println!("  {}. {} (profile: {})", i + 1, entry.remote_url, entry.profile);
                         }
                     }
                     return Ok(());
@@ -223,13 +1366,13 @@ async fn main() -> Result<()> {
 
         // Delete key if requested
         if args.delete_key {
-            match keychain::delete_api_key(&args.remote_url) {
+            match keychain::delete_api_key(&args.remote_url, &args.profile) {
                 Ok(_) => {
                     info!(
                         "✅ API key successfully deleted from macOS Keychain for {}",
                         args.remote_url
                     );
-                    if args.api_key.is_none() {
+                    if args.api_key.is_empty() {
                         // Exit if we're only deleting the key
                         return Ok(());
                     }
@@ -242,31 +1385,29 @@ async fn main() -> Result<()> {
         }
 
         // Save key if provided and save requested
-        if let Some(key) = &args.api_key {
-            if args.save_key {
-                match keychain::save_api_key(key, &args.remote_url) {
-                    Ok(_) => info!(
-                        "✅ API key successfully saved to macOS Keychain for {}",
-                        args.remote_url
-                    ),
-                    Err(e) => warn!(
-                        "❌ Failed to save API key to keychain for {}: {}",
-                        args.remote_url, e
-                    ),
-                }
+        if !args.api_key.is_empty() && args.save_key {
+            // Several keys are stored comma-separated under the same entry
+            match keychain::save_api_key(&args.api_key.join(","), &args.remote_url, &args.profile) {
+                Ok(_) => info!(
+                    "✅ API key successfully saved to macOS Keychain for {}",
+                    args.remote_url
+                ),
+                Err(e) => warn!(
+                    "❌ Failed to save API key to keychain for {}: {}",
+                    args.remote_url, e
+                ),
             }
         }
 
         // Try to get key from keychain if not provided but use_keychain is true
-        if args.api_key.is_none() && args.use_keychain {
-            match keychain::get_api_key(&args.remote_url) {
-                Ok(key) => {
+        if args.api_key.is_empty() && args.use_keychain {
+            match load_stored_keys(&args.remote_url, &args.profile) {
+                Ok(keys) => {
                     info!(
-                        "Using API key from macOS Keychain for {} (length: {})",
-                        args.remote_url,
-                        key.len()
+                        "Using API key from macOS Keychain for {} (profile: {})",
+                        args.remote_url, args.profile
                     );
-                    args.api_key = Some(key);
+                    args.api_key = keys;
                 }
                 Err(e) => {
                     if args.use_keychain {
@@ -280,17 +1421,42 @@ async fn main() -> Result<()> {
         }
     }
 
+    // Load the configuration file, if any
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    // Set up authentication towards the remote
+    args.api_key.retain(|k| !k.is_empty());
+    let auth = build_upstream_auth(&args, &args.api_key, args.ollama_key.as_deref())?;
+
+    // Routes may pick another stored profile for the same remote
+    let mut profile_auth = HashMap::new();
+    for profile in config.routes.iter().filter_map(|route| route.profile.as_ref()) {
+        if profile_auth.contains_key(profile) {
+            continue;
+        }
+        let keys = load_stored_keys(&args.remote_url, profile)
+            .with_context(|| format!("Failed to load profile '{}' used by a route", profile))?;
+        let profile_auth_mode = build_upstream_auth(&args, &keys, None)?;
+        info!("Profile '{}' authentication: {}", profile, profile_auth_mode.describe());
+        profile_auth.insert(profile.clone(), profile_auth_mode);
+    }
+
     info!("Starting Ollama proxy server...");
     info!("Local address: {}", args.local_addr);
     info!("Remote URL: {}", args.remote_url);
-    info!(
-        "API key authentication: {}",
-        if let Some(key) = &args.api_key {
-            format!("enabled (length: {})", key.len())
-        } else {
-            "disabled".to_string()
-        }
-    );
+    info!("Upstream authentication: {}", auth.describe());
+    info!("Client authorization policy: {}", args.client_auth);
+    if let Some(path) = &args.config {
+        info!(
+            "Config file: {} ({} routes, {} clients)",
+            path.display(),
+            config.routes.len(),
+            config.clients.len()
+        );
+    }
     if keychain::is_keychain_enabled() {
         info!("macOS Keychain support: enabled (per remote URL)");
     } else {
@@ -302,22 +1468,142 @@ async fn main() -> Result<()> {
         anyhow::bail!("Remote URL must start with http:// or https://");
     }
 
-    // Create HTTPS client with timeouts suitable for streaming
-    let https = HttpsConnector::new();
-    let client = Client::builder()
-        .pool_idle_timeout(std::time::Duration::from_secs(300))
-        .pool_max_idle_per_host(32) // Increase connection pool size
-        .http2_only(false) // Support both HTTP/1.1 and HTTP/2
-        .http2_initial_stream_window_size(1024 * 1024) // 1MB
-        .http2_initial_connection_window_size(1024 * 1024) // 1MB
-        .build::<_, Body>(https);
+    let access_log = match &args.access_log {
+        Some(destination) => {
+            info!("Access log: {}", destination);
+            Some(AccessLog::open(
+                destination,
+                args.access_log_max_mb * 1024 * 1024,
+                args.access_log_max_files,
+                args.access_log_redact.clone(),
+            )?)
+        }
+        None => None,
+    };
+
+    let upstream = args
+        .remote_url
+        .parse::<hyper::Uri>()
+        .ok()
+        .and_then(|uri| uri.authority().map(|authority| authority.to_string()))
+        .context("Remote URL has no host")?;
+
+    // Models in the config file always get their own series, like those named on the command line
+    let mut metrics_models = args.metrics_model.clone();
+    metrics_models.extend(config.models.iter().map(|model| model.name.clone()));
+    let metrics = Metrics::new(&upstream, metrics_models, args.metrics_max_models)?;
+
+    let ledger = match &args.usage_db {
+        Some(path) => {
+            info!("Usage database: {}", path.display());
+            Some(Ledger::start(path, args.usage_retention_days)?)
+        }
+        None => None,
+    };
+
+    let budgets = Budgets::load(&config.budgets, args.usage_db.as_deref())?;
+    let rate_limiter = RateLimiter::new(&config.routes)?;
+    if args.adaptive_concurrency && !(args.latency_tolerance > 1.0 && args.latency_tolerance.is_finite()) {
+        anyhow::bail!("--latency-tolerance must be more than 1");
+    }
+    let scheduler = Scheduler::new(
+        SchedulerSettings {
+            max_concurrency: args.max_concurrency,
+            model_limits: config
+                .models
+                .iter()
+                .filter_map(|model| Some((model.name.clone(), model.max_concurrency?)))
+                .collect(),
+            weights: config
+                .clients
+                .iter()
+                .filter_map(|client| Some((client.name.clone(), client.weight?)))
+                .collect(),
+            max_queue: args.max_queue,
+            queue_timeout: Duration::from_secs(args.queue_timeout),
+            batch_every: args.batch_every,
+            adaptive: args.adaptive_concurrency,
+            min_concurrency: args.min_concurrency,
+            latency_tolerance: args.latency_tolerance,
+        },
+        &metrics,
+    );
+
+    // Routes and models may connect with their own timeouts, each of which needs its own client
+    let timeouts = default_timeouts(&args);
+    let mut clients = HashMap::new();
+    let configured = config
+        .routes
+        .iter()
+        .filter_map(|route| route.timeouts.as_ref())
+        .chain(config.models.iter().filter_map(|model| model.timeouts.as_ref()));
+    let connect_timeouts = configured.map(|config| timeouts.with(Some(config)).connect);
+    for connect_timeout in std::iter::once(timeouts.connect).chain(connect_timeouts) {
+        clients.entry(connect_timeout).or_insert_with(|| build_client(connect_timeout));
+    }
+
+    let tracer = match &args.otlp_endpoint {
+        Some(endpoint) => {
+            info!("Exporting traces to {}", endpoint);
+            let client = clients[&timeouts.connect].clone();
+            Tracer::start(endpoint, &args.otlp_header, &args.otel_service_name, client)?
+        }
+        None => Tracer::disabled(),
+    };
+
+    let retries = RetryPolicy::new(
+        args.max_attempts,
+        Duration::from_millis(args.retry_backoff_ms),
+        Duration::from_secs(args.retry_max_wait),
+        args.retry_budget,
+    );
+
+    // Request bodies are only read ahead for the model when something uses it
+    let inspect_bodies = args.admin_addr.is_some()
+        || access_log.is_some()
+        || tracer.is_enabled()
+        || ledger.is_some()
+        || !config.budgets.is_empty()
+        || !config.models.is_empty()
+        || scheduler.is_enabled();
 
     // Create shared state
     let state = Arc::new(AppState {
-        client,
+        inspect_bodies,
+        clients,
+        timeouts,
+        retries,
         args: args.clone(),
+        config,
+        auth,
+        profile_auth,
+        forwarding: ForwardingSettings {
+            forwarded_for: args.x_forwarded_for,
+            forwarded_proto: args.x_forwarded_proto,
+            via: args.via,
+            trusted_proxies: args.trusted_proxies.clone(),
+        },
+        access_log,
+        metrics,
+        tracer,
+        usage: UsageTotals::default(),
+        ledger,
+        budgets,
+        rate_limiter,
+        scheduler,
+        upstream,
     });
 
+    // Start the admin server if requested
+    if let Some(admin_addr) = args.admin_addr.clone() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(&admin_addr, state).await {
+                error!("{:#}", e);
+            }
+        });
+    }
+
     // Bind to the local address
     let addr: SocketAddr = args
         .local_addr
@@ -325,9 +1611,15 @@ async fn main() -> Result<()> {
         .context("Failed to parse local address")?;
 
     // Create the service
-    let make_service = make_service_fn(move |_| {
-        let state = state.clone();
-        async move { Ok::<_, hyper::Error>(service_fn(move |req| proxy_handler(req, state.clone()))) }
+    let service_state = state.clone();
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let state = service_state.clone();
+        let client_addr = conn.remote_addr();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| {
+                proxy_handler(req, state.clone(), client_addr)
+            }))
+        }
     });
 
     // Start the server
@@ -347,18 +1639,23 @@ async fn main() -> Result<()> {
     };
 
     // Run the server with graceful shutdown
-    tokio::select! {
-        result = server => {
-            result.context("Server error")?;
-        },
+    let served = tokio::select! {
+        result = server => result.context("Server error"),
         _ = shutdown_signal => {
             info!("Server shutting down...");
+            Ok(())
         },
         _ = rx => {
             info!("Shutdown signal received, server shutting down...");
+            Ok(())
         }
-    }
+    };
 
+    // Usage still waiting to be written goes to disk before the process exits
+    if let Some(ledger) = &state.ledger {
+        ledger.close();
+    }
+    served?;
     info!("Server shutdown complete");
     Ok(())
 }
@@ -0,0 +1,478 @@
+//! Prometheus metrics
+//!
+//! Exposed in the text format at `/metrics` on the admin address. Every label
+//! comes from a bounded set: paths are reduced to the known Ollama and OpenAI
+//! endpoints, and models outside the `--metrics-model` list only get their own
+//! series until `--metrics-max-models` have been seen. Anything else is
+//! reported as `other`. Clients are the names from the config file, or
+//! `unknown`.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use prometheus::{
+    Encoder, Gauge, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::retry::{Failure, Skipped};
+use crate::timeouts::Phase;
+use crate::usage::Usage;
+
+// Label for endpoints and models that don't get their own series
+const OTHER: &str = "other";
+
+// Label for requests that don't name a model
+const NO_MODEL: &str = "none";
+
+// Label for requests from clients not listed in the config file
+const UNKNOWN_CLIENT: &str = "unknown";
+
+/// Paths reported as their own endpoint; anything below them is folded in
+const ENDPOINTS: &[&str] = &[
+    "/api/chat",
+    "/api/generate",
+    "/api/embed",
+    "/api/embeddings",
+    "/api/tags",
+    "/api/show",
+    "/api/ps",
+    "/api/pull",
+    "/api/push",
+    "/api/create",
+    "/api/copy",
+    "/api/delete",
+    "/api/blobs",
+    "/api/version",
+    "/v1/chat/completions",
+    "/v1/completions",
+    "/v1/embeddings",
+    "/v1/models",
+];
+
+// Generation can take minutes, so the buckets reach well past typical HTTP latencies
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+// Pauses between chunks of a healthy stream are tens of milliseconds
+const GAP_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+const THROUGHPUT_BUCKETS: &[f64] = &[
+    1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 50.0, 75.0, 100.0, 150.0, 200.0, 300.0, 500.0,
+];
+
+/// Labels fixed when a request arrives
+#[derive(Clone)]
+pub struct RequestLabels {
+    endpoint: &'static str,
+    model: String,
+    client: String,
+}
+
+impl RequestLabels {
+    /// The known endpoint the request went to, or `other`
+    pub fn endpoint(&self) -> &'static str {
+        self.endpoint
+    }
+
+    /// The configured client's name, or `unknown`
+    pub fn client(&self) -> &str {
+        &self.client
+    }
+}
+
+/// What is known about a request once its response has been sent
+pub struct Completed {
+    pub status: u16,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub first_byte: Duration,
+    pub duration: Duration,
+}
+
+pub struct Metrics {
+    registry: Registry,
+    upstream: String,
+    models: ModelLabels,
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    first_byte: HistogramVec,
+    in_flight: IntGaugeVec,
+    bytes_in: IntCounterVec,
+    bytes_out: IntCounterVec,
+    tokens: IntCounterVec,
+    first_token: HistogramVec,
+    chunk_gap: HistogramVec,
+    throughput: HistogramVec,
+    cancelled: IntCounterVec,
+    connect_errors: IntCounterVec,
+    timeouts: IntCounterVec,
+    retries: IntCounterVec,
+    retries_skipped: IntCounterVec,
+    queue_depth: IntGaugeVec,
+    queue_wait: HistogramVec,
+    queue_rejections: IntCounterVec,
+    concurrency_limit: GaugeVec,
+}
+
+impl Metrics {
+    pub fn new(upstream: &str, models: Vec<String>, max_models: usize) -> Result<Self> {
+        let registry = Registry::new_custom(Some("ollama_agent".to_string()), None)?;
+        let request_labels = &["endpoint", "model", "upstream"];
+
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "Requests handled, by response status"),
+            &["endpoint", "model", "upstream", "status"],
+        )?;
+        let duration = HistogramVec::new(
+            HistogramOpts::new(
+                "request_duration_seconds",
+                "Time from receiving a request to sending the last byte of the response",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            request_labels,
+        )?;
+        let first_byte = HistogramVec::new(
+            HistogramOpts::new(
+                "time_to_first_byte_seconds",
+                "Time from receiving a request to sending the first byte of the response body",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            request_labels,
+        )?;
+        let in_flight = IntGaugeVec::new(
+            Opts::new("requests_in_flight", "Requests received whose response is not finished"),
+            request_labels,
+        )?;
+        let bytes_in = IntCounterVec::new(
+            Opts::new("request_bytes_total", "Request body bytes received from clients"),
+            request_labels,
+        )?;
+        let bytes_out = IntCounterVec::new(
+            Opts::new("response_bytes_total", "Response body bytes streamed to clients"),
+            request_labels,
+        )?;
+        let tokens = IntCounterVec::new(
+            Opts::new("tokens_total", "Tokens reported by the remote, by kind (prompt or completion)"),
+            &["model", "client", "upstream", "kind"],
+        )?;
+        let first_token = HistogramVec::new(
+            HistogramOpts::new(
+                "time_to_first_token_seconds",
+                "Time from receiving a chat or generate request to relaying the first generated output",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["model", "upstream"],
+        )?;
+        let chunk_gap = HistogramVec::new(
+            HistogramOpts::new(
+                "inter_chunk_gap_seconds",
+                "Time between consecutive chunks of streamed chat and generate responses",
+            )
+            .buckets(GAP_BUCKETS.to_vec()),
+            &["model", "upstream"],
+        )?;
+        let throughput = HistogramVec::new(
+            HistogramOpts::new(
+                "generation_tokens_per_second",
+                "Completion tokens per second of generation, as reported by the remote",
+            )
+            .buckets(THROUGHPUT_BUCKETS.to_vec()),
+            &["model", "upstream"],
+        )?;
+        let cancelled = IntCounterVec::new(
+            Opts::new(
+                "requests_cancelled_total",
+                "Requests the client gave up on before the response finished, by how far they had got",
+            ),
+            &["endpoint", "model", "upstream", "stage"],
+        )?;
+        let connect_errors = IntCounterVec::new(
+            Opts::new("upstream_connect_errors_total", "Failed connection attempts to the remote"),
+            &["upstream"],
+        )?;
+        let timeouts = IntCounterVec::new(
+            Opts::new(
+                "upstream_timeouts_total",
+                "Remote requests that ran out of time, by the timeout that expired",
+            ),
+            &["upstream", "timeout"],
+        )?;
+        let retries = IntCounterVec::new(
+            Opts::new("upstream_retries_total", "Remote requests sent again, by what went wrong with the last attempt"),
+            &["upstream", "reason"],
+        )?;
+        let retries_skipped = IntCounterVec::new(
+            Opts::new(
+                "upstream_retries_skipped_total",
+                "Failed remote requests that could have been retried but weren't, by why not",
+            ),
+            &["upstream", "reason"],
+        )?;
+        let queue_depth = IntGaugeVec::new(
+            Opts::new("queue_depth", "Requests waiting for a slot under a concurrency limit, by priority class"),
+            &["upstream", "class"],
+        )?;
+        let queue_wait = HistogramVec::new(
+            HistogramOpts::new(
+                "queue_wait_seconds",
+                "Time requests waited for a slot under a concurrency limit",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["model", "upstream"],
+        )?;
+        let queue_rejections = IntCounterVec::new(
+            Opts::new(
+                "queue_rejections_total",
+                "Requests refused because the queue was full, the wait timed out or the deadline \
+                 couldn't be met, by reason",
+            ),
+            &["upstream", "reason"],
+        )?;
+        let concurrency_limit = GaugeVec::new(
+            Opts::new(
+                "concurrency_limit",
+                "Requests allowed to run on the remote at once, as set by the adaptive limit",
+            ),
+            &["upstream"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(duration.clone()))?;
+        registry.register(Box::new(first_byte.clone()))?;
+        registry.register(Box::new(in_flight.clone()))?;
+        registry.register(Box::new(bytes_in.clone()))?;
+        registry.register(Box::new(bytes_out.clone()))?;
+        registry.register(Box::new(tokens.clone()))?;
+        registry.register(Box::new(first_token.clone()))?;
+        registry.register(Box::new(chunk_gap.clone()))?;
+        registry.register(Box::new(throughput.clone()))?;
+        registry.register(Box::new(cancelled.clone()))?;
+        registry.register(Box::new(connect_errors.clone()))?;
+        registry.register(Box::new(timeouts.clone()))?;
+        registry.register(Box::new(retries.clone()))?;
+        registry.register(Box::new(retries_skipped.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+        registry.register(Box::new(queue_wait.clone()))?;
+        registry.register(Box::new(queue_rejections.clone()))?;
+        registry.register(Box::new(concurrency_limit.clone()))?;
+
+        // Export the error counters at zero rather than only after the first error
+        connect_errors.with_label_values(&[upstream]);
+        for phase in Phase::ALL {
+            timeouts.with_label_values(&[upstream, phase.as_str()]);
+        }
+
+        Ok(Metrics {
+            registry,
+            upstream: upstream.to_string(),
+            models: ModelLabels::new(models, max_models),
+            requests,
+            duration,
+            first_byte,
+            in_flight,
+            bytes_in,
+            bytes_out,
+            tokens,
+            first_token,
+            chunk_gap,
+            throughput,
+            cancelled,
+            connect_errors,
+            timeouts,
+            retries,
+            retries_skipped,
+            queue_depth,
+            queue_wait,
+            queue_rejections,
+            concurrency_limit,
+        })
+    }
+
+    /// Counts a request as in flight and fixes its labels
+    pub fn request_started(&self, path: &str, model: Option<&str>, client: Option<&str>) -> RequestLabels {
+        let labels = RequestLabels {
+            endpoint: endpoint_label(path),
+            model: self.models.label(model),
+            client: client.unwrap_or(UNKNOWN_CLIENT).to_string(),
+        };
+        self.in_flight
+            .with_label_values(&[labels.endpoint, &labels.model, &self.upstream])
+            .inc();
+        labels
+    }
+
+    /// The label `model` is reported under
+    pub fn model_label(&self, model: Option<&str>) -> String {
+        self.models.label(model)
+    }
+
+    /// Notes that the remote answered a request for `model` successfully
+    pub fn model_served(&self, model: &str) {
+        self.models.admit(model);
+    }
+
+    pub fn request_finished(&self, labels: &RequestLabels, completed: &Completed) {
+        let values = [labels.endpoint, labels.model.as_str(), self.upstream.as_str()];
+        self.in_flight.with_label_values(&values).dec();
+        self.requests
+            .with_label_values(&[
+                labels.endpoint,
+                &labels.model,
+                &self.upstream,
+                &completed.status.to_string(),
+            ])
+            .inc();
+        self.duration
+            .with_label_values(&values)
+            .observe(completed.duration.as_secs_f64());
+        self.first_byte
+            .with_label_values(&values)
+            .observe(completed.first_byte.as_secs_f64());
+        self.bytes_in.with_label_values(&values).inc_by(completed.bytes_in);
+        self.bytes_out.with_label_values(&values).inc_by(completed.bytes_out);
+    }
+
+    pub fn record_usage(&self, labels: &RequestLabels, usage: &Usage) {
+        let values = [labels.model.as_str(), labels.client.as_str(), self.upstream.as_str()];
+        self.tokens
+            .with_label_values(&[values[0], values[1], values[2], "prompt"])
+            .inc_by(usage.prompt_eval_count);
+        self.tokens
+            .with_label_values(&[values[0], values[1], values[2], "completion"])
+            .inc_by(usage.eval_count);
+    }
+
+    pub fn observe_first_token(&self, labels: &RequestLabels, elapsed: Duration) {
+        self.first_token
+            .with_label_values(&[&labels.model, &self.upstream])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_chunk_gap(&self, labels: &RequestLabels, gap: Duration) {
+        self.chunk_gap
+            .with_label_values(&[&labels.model, &self.upstream])
+            .observe(gap.as_secs_f64());
+    }
+
+    pub fn observe_throughput(&self, labels: &RequestLabels, tokens_per_second: f64) {
+        self.throughput
+            .with_label_values(&[&labels.model, &self.upstream])
+            .observe(tokens_per_second);
+    }
+
+    pub fn request_cancelled(&self, labels: &RequestLabels, stage: &str) {
+        self.cancelled
+            .with_label_values(&[labels.endpoint, &labels.model, &self.upstream, stage])
+            .inc();
+    }
+
+    pub fn upstream_connect_error(&self) {
+        self.connect_errors.with_label_values(&[&self.upstream]).inc();
+    }
+
+    pub fn upstream_timeout(&self, phase: Phase) {
+        self.timeouts.with_label_values(&[&self.upstream, phase.as_str()]).inc();
+    }
+
+    pub fn upstream_retry(&self, failure: Failure) {
+        self.retries.with_label_values(&[&self.upstream, failure.as_str()]).inc();
+    }
+
+    pub fn retry_skipped(&self, skipped: Skipped) {
+        self.retries_skipped.with_label_values(&[&self.upstream, skipped.as_str()]).inc();
+    }
+
+    /// The gauge the scheduler keeps up to date with a class's queue length
+    pub fn queue_depth(&self, class: &str) -> IntGauge {
+        self.queue_depth.with_label_values(&[&self.upstream, class])
+    }
+
+    /// The gauge the adaptive limit keeps up to date
+    pub fn concurrency_limit(&self) -> Gauge {
+        self.concurrency_limit.with_label_values(&[&self.upstream])
+    }
+
+    pub fn observe_queue_wait(&self, labels: &RequestLabels, waited: Duration) {
+        self.queue_wait
+            .with_label_values(&[&labels.model, &self.upstream])
+            .observe(waited.as_secs_f64());
+    }
+
+    pub fn queue_rejected(&self, reason: &str) {
+        self.queue_rejections.with_label_values(&[&self.upstream, reason]).inc();
+    }
+
+    /// Renders every metric in the Prometheus text format
+    pub fn render(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+/// Content type of [`Metrics::render`]
+pub fn content_type() -> String {
+    TextEncoder::new().format_type().to_string()
+}
+
+fn endpoint_label(path: &str) -> &'static str {
+    let path = path.trim_end_matches('/');
+    ENDPOINTS
+        .iter()
+        .find(|endpoint| {
+            path.strip_prefix(**endpoint)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .copied()
+        .unwrap_or(OTHER)
+}
+
+// Decides which model names get their own series
+struct ModelLabels {
+    allowed: HashSet<String>,
+    max_seen: usize,
+    seen: Mutex<HashSet<String>>,
+}
+
+impl ModelLabels {
+    fn new(allowed: Vec<String>, max_seen: usize) -> Self {
+        ModelLabels {
+            allowed: allowed.into_iter().collect(),
+            max_seen,
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn label(&self, model: Option<&str>) -> String {
+        let Some(model) = model.filter(|model| !model.is_empty()) else {
+            return NO_MODEL.to_string();
+        };
+        if self.allowed.contains(model) {
+            return model.to_string();
+        }
+
+        if self.seen.lock().unwrap().contains(model) {
+            model.to_string()
+        } else {
+            OTHER.to_string()
+        }
+    }
+
+    // Gives a model its own series once the remote has served it, first come,
+    // first served until the cap is reached, so made-up names can't use them up
+    fn admit(&self, model: &str) {
+        if model.is_empty() || self.allowed.contains(model) {
+            return;
+        }
+        let mut seen = self.seen.lock().unwrap();
+        if seen.len() < self.max_seen {
+            seen.insert(model.to_string());
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! Queue progress for waiting clients
+//!
+//! With `--queue-progress`, a streaming chat or generate request that has to
+//! wait for a slot is answered at once: a 200 with its place in the queue in
+//! `X-Queue-Position`, then a space every few seconds to keep the connection
+//! open. JSON parsers skip leading whitespace, so the spaces just end up in
+//! front of the first line of the real response once it is relayed. As the
+//! status has already gone out by then, an error is sent as an
+//! `{"error": ...}` line, the way Ollama reports errors part way through a
+//! stream.
+//!
+//! Any waiting request can also be looked up by its request ID at
+//! `/_agent/queue/{request_id}` on the proxy address, so that a UI can show
+//! "queued, position 3" while it waits.
+
+use std::time::Duration;
+
+use hyper::body::{Bytes, HttpBody, Sender};
+use hyper::header::HeaderName;
+use hyper::{Response, StatusCode};
+use serde_json::json;
+
+use crate::body::ProxyBody;
+use crate::scheduler::{Permit, Rejected, Ticket};
+
+/// Where waiting requests can be looked up by ID
+pub const STATUS_PATH: &str = "/_agent/queue/";
+
+pub const X_QUEUE_POSITION: HeaderName = HeaderName::from_static("x-queue-position");
+
+const KEEP_ALIVE: Duration = Duration::from_secs(5);
+
+/// Waits for a slot while keeping the stream alive, or returns None if the client goes away
+pub async fn wait(ticket: Ticket, sender: &mut Sender) -> Option<Result<Permit, Rejected>> {
+    let admitted = ticket.wait();
+    tokio::pin!(admitted);
+    let mut keep_alive = tokio::time::interval_at(tokio::time::Instant::now() + KEEP_ALIVE, KEEP_ALIVE);
+    loop {
+        tokio::select! {
+            admitted = &mut admitted => return Some(admitted),
+            _ = keep_alive.tick() => {
+                if sender.send_data(Bytes::from_static(b" ")).await.is_err() {
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Relays a response into a stream whose status has already been sent
+pub async fn relay(response: Response<ProxyBody>, mut sender: Sender) {
+    let status = response.status();
+    let mut body = response.into_body();
+    if !status.is_success() {
+        let bytes = hyper::body::to_bytes(body).await.unwrap_or_default();
+        let _ = sender.send_data(error_line(status, &bytes)).await;
+        return;
+    }
+
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => {
+                // The client went away; dropping the body tells the remote
+                if sender.send_data(chunk).await.is_err() {
+                    return;
+                }
+            }
+            Err(_) => {
+                sender.abort();
+                return;
+            }
+        }
+    }
+}
+
+// Turns an error response into an NDJSON line, keeping an Ollama-style body as it is
+fn error_line(status: StatusCode, body: &[u8]) -> Bytes {
+    let value = match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(value) if value.get("error").is_some() => value,
+        _ => {
+            let text = String::from_utf8_lossy(body);
+            let text = text.trim();
+            if text.is_empty() {
+                json!({ "error": status.to_string() })
+            } else {
+                json!({ "error": format!("{}: {}", status, text) })
+            }
+        }
+    };
+    let mut line = value.to_string().into_bytes();
+    line.push(b'\n');
+    line.into()
+}
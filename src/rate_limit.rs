@@ -0,0 +1,295 @@
+//! Request rate limits
+//!
+//! A route's `rate_limit` gives every client (or every source address) a
+//! token bucket: requests spend a token each, tokens come back at
+//! `requests_per_second`, and up to `burst` can be saved up. A request that
+//! finds the bucket empty is refused with a 429 before anything is sent to
+//! the remote.
+//!
+//! ```toml
+//! [[routes]]
+//! prefix = "/api/"
+//! rate_limit = { requests_per_second = 2, burst = 10 }
+//! ```
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use hyper::header::{HeaderMap, HeaderValue};
+use serde::Deserialize;
+
+use crate::config::RouteConfig;
+
+// How often buckets that have filled up again are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub requests_per_second: f64,
+    /// Requests that can be made at once after a quiet spell [default: one second's worth]
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub key: RateLimitKey,
+}
+
+impl RateLimitConfig {
+    fn burst(&self) -> f64 {
+        self.burst
+            .map(f64::from)
+            .unwrap_or_else(|| self.requests_per_second.ceil().max(1.0))
+    }
+}
+
+/// Who shares a bucket
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitKey {
+    /// The client name, or the source address for unknown clients
+    #[default]
+    Client,
+    /// The source address, even for known clients
+    Ip,
+}
+
+/// The outcome of a rate limit check, and the headers that describe it
+pub struct RateDecision {
+    pub allowed: bool,
+    limit: u32,
+    remaining: u32,
+    // Until the bucket is full again
+    reset: Duration,
+    // Until the next request would be allowed
+    retry_after: Duration,
+    window: Duration,
+}
+
+impl RateDecision {
+    /// Adds the RateLimit headers, and `Retry-After` to a refusal
+    pub fn set_headers(&self, headers: &mut HeaderMap) {
+        let secs = |duration: Duration| duration.as_secs_f64().ceil() as u64;
+        headers.insert("ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("ratelimit-reset", HeaderValue::from(secs(self.reset)));
+        if let Ok(policy) = HeaderValue::from_str(&format!("{};w={}", self.limit, secs(self.window).max(1))) {
+            headers.insert("ratelimit-policy", policy);
+        }
+        if !self.allowed {
+            headers.insert(hyper::header::RETRY_AFTER, HeaderValue::from(secs(self.retry_after).max(1)));
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    rate: f64,
+    burst: f64,
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant) -> f64 {
+        (self.tokens + now.duration_since(self.updated).as_secs_f64() * self.rate).min(self.burst)
+    }
+}
+
+struct Buckets {
+    // By route prefix and client name or address
+    buckets: HashMap<(String, String), Bucket>,
+    last_sweep: Instant,
+}
+
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// Checks the rate limits of the configured routes
+    pub fn new(routes: &[RouteConfig]) -> Result<Self> {
+        for route in routes {
+            if let Some(limit) = &route.rate_limit {
+                if !(limit.requests_per_second > 0.0 && limit.requests_per_second.is_finite()) {
+                    bail!("Route {}: requests_per_second must be positive", route.prefix);
+                }
+                if limit.burst == Some(0) {
+                    bail!("Route {}: burst must be at least 1", route.prefix);
+                }
+            }
+        }
+        Ok(RateLimiter {
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        })
+    }
+
+    /// Takes a token for the request, or None if the route has no rate limit
+    pub fn check(&self, route: &RouteConfig, client: Option<&str>, ip: IpAddr) -> Option<RateDecision> {
+        self.check_at(route, client, ip, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        route: &RouteConfig,
+        client: Option<&str>,
+        ip: IpAddr,
+        now: Instant,
+    ) -> Option<RateDecision> {
+        let limit = route.rate_limit.as_ref()?;
+        let rate = limit.requests_per_second;
+        let burst = limit.burst();
+        let key = match (limit.key, client) {
+            (RateLimitKey::Client, Some(client)) => client.to_string(),
+            _ => ip.to_string(),
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.duration_since(buckets.last_sweep) >= SWEEP_INTERVAL {
+            // A bucket that would be full by now is the same as no bucket
+            buckets.buckets.retain(|_, bucket| bucket.refilled(now) < bucket.burst);
+            buckets.last_sweep = now;
+        }
+
+        let bucket = buckets
+            .buckets
+            .entry((route.prefix.clone(), key))
+            .or_insert(Bucket {
+                tokens: burst,
+                updated: now,
+                rate,
+                burst,
+            });
+        bucket.tokens = bucket.refilled(now);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        // A tiny rate makes these too long for a Duration
+        let secs = |secs: f64| Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX);
+        Some(RateDecision {
+            allowed,
+            limit: burst as u32,
+            remaining: bucket.tokens.floor() as u32,
+            reset: secs((burst - bucket.tokens) / rate),
+            retry_after: secs((1.0 - bucket.tokens).max(0.0) / rate),
+            window: secs(burst / rate),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(requests_per_second: f64, burst: Option<u32>, key: RateLimitKey) -> RouteConfig {
+        RouteConfig {
+            prefix: "/api/".to_string(),
+            client_auth: None,
+            profile: None,
+            rate_limit: Some(RateLimitConfig {
+                requests_per_second,
+                burst,
+                key,
+            }),
+            priority: None,
+            timeouts: None,
+            max_attempts: None,
+        }
+    }
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn spends_the_burst_then_refuses() {
+        let route = route(1.0, Some(3), RateLimitKey::Client);
+        let limiter = RateLimiter::new(std::slice::from_ref(&route)).unwrap();
+        let now = Instant::now();
+        for remaining in [2, 1, 0] {
+            let decision = limiter.check_at(&route, Some("alice"), IP, now).unwrap();
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let decision = limiter.check_at(&route, Some("alice"), IP, now).unwrap();
+        assert!(!decision.allowed);
+        assert_eq!(decision.limit, 3);
+        assert_eq!(decision.retry_after, Duration::from_secs(1));
+        assert_eq!(decision.reset, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let route = route(2.0, Some(2), RateLimitKey::Client);
+        let limiter = RateLimiter::new(std::slice::from_ref(&route)).unwrap();
+        let now = Instant::now();
+        assert!(limiter.check_at(&route, Some("alice"), IP, now).unwrap().allowed);
+        assert!(limiter.check_at(&route, Some("alice"), IP, now).unwrap().allowed);
+        assert!(!limiter.check_at(&route, Some("alice"), IP, now).unwrap().allowed);
+
+        let decision = limiter.check_at(&route, Some("alice"), IP, now + Duration::from_millis(250)).unwrap();
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after, Duration::from_millis(250));
+        assert!(limiter.check_at(&route, Some("alice"), IP, now + Duration::from_millis(500)).unwrap().allowed);
+
+        // Never more than the burst, however long the bucket sits
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(limiter.check_at(&route, Some("alice"), IP, later).unwrap().remaining, 1);
+    }
+
+    #[test]
+    fn buckets_are_per_client() {
+        let route = route(1.0, Some(1), RateLimitKey::Client);
+        let limiter = RateLimiter::new(std::slice::from_ref(&route)).unwrap();
+        let now = Instant::now();
+        assert!(limiter.check_at(&route, Some("alice"), IP, now).unwrap().allowed);
+        let refused = limiter.check_at(&route, Some("alice"), IP, now + Duration::from_millis(400)).unwrap();
+        assert!(!refused.allowed);
+        assert_eq!(refused.retry_after, Duration::from_millis(600));
+
+        let mut headers = HeaderMap::new();
+        refused.set_headers(&mut headers);
+        assert_eq!(headers["retry-after"], "1");
+        assert_eq!(headers["ratelimit-remaining"], "0");
+
+        assert!(limiter.check_at(&route, Some("bob"), IP, now).unwrap().allowed);
+        // Unknown clients share a bucket by address
+        assert!(limiter.check_at(&route, None, IP, now).unwrap().allowed);
+        assert!(!limiter.check_at(&route, None, IP, now).unwrap().allowed);
+    }
+
+    #[test]
+    fn ip_key_ignores_the_client() {
+        let route = route(1.0, Some(1), RateLimitKey::Ip);
+        let limiter = RateLimiter::new(std::slice::from_ref(&route)).unwrap();
+        let now = Instant::now();
+        assert!(limiter.check_at(&route, Some("alice"), IP, now).unwrap().allowed);
+        assert!(!limiter.check_at(&route, Some("bob"), IP, now).unwrap().allowed);
+    }
+
+    #[test]
+    fn tiny_rate_does_not_panic() {
+        let route = route(1e-300, Some(1), RateLimitKey::Client);
+        let limiter = RateLimiter::new(std::slice::from_ref(&route)).unwrap();
+        let now = Instant::now();
+        assert!(limiter.check_at(&route, Some("alice"), IP, now).unwrap().allowed);
+        let decision = limiter.check_at(&route, Some("alice"), IP, now).unwrap();
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after, Duration::MAX);
+
+        let mut headers = HeaderMap::new();
+        decision.set_headers(&mut headers);
+        assert!(headers.contains_key("retry-after"));
+    }
+
+    #[test]
+    fn rejects_bad_limits() {
+        assert!(RateLimiter::new(&[route(0.0, None, RateLimitKey::Client)]).is_err());
+        assert!(RateLimiter::new(&[route(f64::NAN, None, RateLimitKey::Client)]).is_err());
+        assert!(RateLimiter::new(&[route(1.0, Some(0), RateLimitKey::Client)]).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! The `usage` subcommand
+//!
+//! Summarises the usage ledger written by a proxy running with `--usage-db`,
+//! grouped by any of client, model, upstream and day.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use clap::{Subcommand, ValueEnum};
+use serde_json::{json, Map, Value};
+
+use crate::ledger;
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum UsageCommand {
+    /// Print token usage totals from the usage database
+    Report {
+        /// Start of the period, as YYYY-MM-DD or an RFC 3339 time (UTC)
+        #[arg(long)]
+        since: Option<String>,
+
+        /// End of the period (exclusive), as YYYY-MM-DD or an RFC 3339 time (UTC)
+        #[arg(long)]
+        until: Option<String>,
+
+        /// Columns to group by: client, model, upstream, day; comma-separate
+        #[arg(long, value_delimiter = ',', default_value = "client,model")]
+        group_by: Vec<String>,
+
+        /// Output format
+        #[arg(long, value_enum, default_value = "table")]
+        format: ReportFormat,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Table,
+    Csv,
+    Json,
+}
+
+// Grouping columns and the SQL that produces them
+const GROUPS: &[(&str, &str)] = &[
+    ("client", "client"),
+    ("model", "model"),
+    ("upstream", "upstream"),
+    ("day", "strftime('%Y-%m-%d', timestamp_ms / 1000, 'unixepoch')"),
+];
+
+const TOTALS: &[&str] = &["requests", "prompt_tokens", "completion_tokens", "total_tokens", "duration_s"];
+
+pub fn run(command: &UsageCommand, db: Option<&Path>) -> Result<()> {
+    let Some(db) = db else {
+        bail!("No usage database given; pass --usage-db");
+    };
+    if !db.exists() {
+        bail!("Usage database {} does not exist", db.display());
+    }
+
+    match command {
+        UsageCommand::Report {
+            since,
+            until,
+            group_by,
+            format,
+        } => {
+            if group_by.is_empty() {
+                bail!("Give at least one column to --group-by");
+            }
+            let mut group_sql = Vec::new();
+            for name in group_by {
+                let Some((_, sql)) = GROUPS.iter().find(|(group, _)| group == name) else {
+                    let names: Vec<&str> = GROUPS.iter().map(|(group, _)| *group).collect();
+                    bail!("Unknown group '{}' (groups: {})", name, names.join(", "));
+                };
+                group_sql.push(*sql);
+            }
+
+            let since = since.as_deref().map(parse_time).transpose()?.unwrap_or(i64::MIN);
+            let until = until.as_deref().map(parse_time).transpose()?.unwrap_or(i64::MAX);
+            let rows = query(db, group_by, &group_sql, since, until)?;
+            print_report(group_by, &rows, *format)
+        }
+    }
+}
+
+// A group's key values followed by its totals
+type ReportRow = (Vec<String>, [f64; 5]);
+
+fn query(db: &Path, groups: &[String], group_sql: &[&str], since: i64, until: i64) -> Result<Vec<ReportRow>> {
+    let conn = ledger::open(db)?;
+    let keys = group_sql.join(", ");
+    let sql = format!(
+        "SELECT {keys}, COUNT(*), SUM(prompt_tokens), SUM(completion_tokens), SUM(duration_ms)
+         FROM usage WHERE timestamp_ms >= ?1 AND timestamp_ms < ?2
+         GROUP BY {keys} ORDER BY {keys}",
+        keys = keys
+    );
+
+    let key_count = groups.len();
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map([since, until], |row| {
+        let mut key = Vec::new();
+        for i in 0..key_count {
+            key.push(row.get::<_, String>(i)?);
+        }
+        let requests: i64 = row.get(key_count)?;
+        let prompt: i64 = row.get(key_count + 1)?;
+        let completion: i64 = row.get(key_count + 2)?;
+        let duration_ms: i64 = row.get(key_count + 3)?;
+        Ok((
+            key,
+            [
+                requests as f64,
+                prompt as f64,
+                completion as f64,
+                (prompt + completion) as f64,
+                duration_ms as f64 / 1000.0,
+            ],
+        ))
+    })?;
+    rows.collect::<rusqlite::Result<_>>()
+        .context("Failed to read the usage database")
+}
+
+fn print_report(groups: &[String], rows: &[ReportRow], format: ReportFormat) -> Result<()> {
+    let header: Vec<&str> = groups.iter().map(String::as_str).chain(TOTALS.iter().copied()).collect();
+    let cells = |(key, totals): &ReportRow| -> Vec<String> {
+        let totals = totals.iter().enumerate().map(|(i, value)| {
+            // Everything but the duration is a count
+            if i == TOTALS.len() - 1 {
+                format!("{:.1}", value)
+            } else {
+                format!("{}", *value as u64)
+            }
+        });
+        key.iter().cloned().chain(totals).collect()
+    };
+
+    match format {
+        ReportFormat::Table => {
+            let lines: Vec<Vec<String>> = rows.iter().map(cells).collect();
+            let widths: Vec<usize> = header
+                .iter()
+                .enumerate()
+                .map(|(i, name)| lines.iter().map(|line| line[i].len()).fold(name.len(), usize::max))
+                .collect();
+            let numeric_from = groups.len();
+            let format_line = |line: &[String]| -> String {
+                line.iter()
+                    .enumerate()
+                    .map(|(i, cell)| {
+                        if i < numeric_from {
+                            format!("{:<width$}", cell, width = widths[i])
+                        } else {
+                            format!("{:>width$}", cell, width = widths[i])
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join("  ")
+            };
+
+            let header: Vec<String> = header.iter().map(|name| name.to_uppercase()).collect();
+            println!("{}", format_line(&header).trim_end());
+            for line in &lines {
+                println!("{}", format_line(line).trim_end());
+            }
+            if lines.is_empty() {
+                println!("(no usage in this period)");
+            }
+        }
+        ReportFormat::Csv => {
+            println!("{}", header.join(","));
+            for row in rows {
+                let line: Vec<String> = cells(row).iter().map(|cell| csv_field(cell)).collect();
+                println!("{}", line.join(","));
+            }
+        }
+        ReportFormat::Json => {
+            let objects: Vec<Value> = rows
+                .iter()
+                .map(|(key, totals)| {
+                    let mut object = Map::new();
+                    for (name, value) in groups.iter().zip(key) {
+                        object.insert(name.clone(), json!(value));
+                    }
+                    for (i, name) in TOTALS.iter().enumerate() {
+                        let value = if i == TOTALS.len() - 1 {
+                            json!(totals[i])
+                        } else {
+                            json!(totals[i] as u64)
+                        };
+                        object.insert(name.to_string(), value);
+                    }
+                    Value::Object(object)
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&objects)?);
+        }
+    }
+    Ok(())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Accepts a bare date as midnight UTC
+fn parse_time(value: &str) -> Result<i64> {
+    let full = if value.len() == 10 {
+        format!("{}T00:00:00Z", value)
+    } else {
+        value.to_string()
+    };
+    let time = humantime::parse_rfc3339_weak(&full)
+        .with_context(|| format!("Invalid time '{}', expected YYYY-MM-DD or RFC 3339", value))?;
+    Ok(ledger::unix_millis(time))
+}
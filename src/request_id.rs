@@ -0,0 +1,37 @@
+//! Request IDs
+//!
+//! Every request gets an ID that ties together the client, the proxy's log
+//! and the remote: the client's own `X-Request-Id` when it sent a usable one,
+//! otherwise a fresh ULID. The ID is forwarded to the remote and echoed back
+//! on the response.
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Longer client IDs are replaced rather than copied into every log line
+const MAX_LEN: usize = 128;
+
+/// Returns the client's `X-Request-Id`, or a new ULID if it sent none or an unusable one
+pub fn from_headers(headers: &HeaderMap) -> String {
+    headers
+        .get(X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| is_valid(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| ulid::Ulid::new().to_string())
+}
+
+/// Sets `X-Request-Id`, replacing any value already there
+pub fn set(headers: &mut HeaderMap, id: &str) {
+    if let Ok(value) = HeaderValue::from_str(id) {
+        headers.insert(X_REQUEST_ID, value);
+    }
+}
+
+// Visible ASCII only, so the ID can't break up or forge log lines
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
@@ -0,0 +1,230 @@
+//! Retrying remote requests that failed safely
+//!
+//! A request is sent again when the remote couldn't be reached, answered with
+//! a 503, or answered with a 429 that says when to come back, up to
+//! `--max-attempts` times in all (a route can set its own `max_attempts`).
+//! Only failures that came before any of the response went to the client are
+//! retried, and only for requests whose body was read in full up front, since
+//! a streamed body can't be sent twice.
+//!
+//! Retries wait an exponential backoff with jitter, or as long as the
+//! remote's `Retry-After` asks for if it asks for no more than
+//! `--retry-max-wait`. They also draw on a budget shared by all requests, so
+//! that a remote in trouble isn't sent a multiple of its usual load: every
+//! request adds `--retry-budget` of a retry to it, up to a reserve of ten.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::StatusCode;
+
+// Retries the budget can save up, and starts with
+const BUDGET_RESERVE: f64 = 10.0;
+
+/// Why an attempt is worth making again
+#[derive(Debug, Clone, Copy)]
+pub enum Failure {
+    /// The connection to the remote was refused or failed
+    Connect,
+    /// The connection to the remote took longer than the connect timeout
+    ConnectTimeout,
+    /// The remote answered with a status that says to try again later
+    Status(StatusCode),
+}
+
+impl Failure {
+    /// A response worth retrying, if it is one. A 429 without `Retry-After` may
+    /// be a quota that won't come back soon, and a 502 may have come after the
+    /// remote started on the request.
+    pub fn from_status(status: StatusCode, retry_after: Option<Duration>) -> Option<Self> {
+        let retry = match status {
+            StatusCode::TOO_MANY_REQUESTS => retry_after.is_some(),
+            StatusCode::SERVICE_UNAVAILABLE => true,
+            _ => false,
+        };
+        retry.then_some(Failure::Status(status))
+    }
+
+    /// Label for the metrics
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Failure::Connect => "connect_error",
+            Failure::ConnectTimeout => "connect_timeout",
+            Failure::Status(StatusCode::TOO_MANY_REQUESTS) => "429",
+            Failure::Status(_) => "503",
+        }
+    }
+}
+
+/// Why a failed attempt was not retried
+#[derive(Debug, Clone, Copy)]
+pub enum Skipped {
+    /// The request used up its attempts
+    Attempts,
+    /// The request body was streamed to the remote, so it can't be sent again
+    StreamedBody,
+    /// Retries across all requests are over budget
+    Budget,
+    /// The remote asked for a longer wait than `--retry-max-wait`
+    RetryAfter(Duration),
+    /// The wait would take the request past its deadline
+    Deadline,
+}
+
+impl Skipped {
+    /// Label for the metrics
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Skipped::Attempts => "attempts",
+            Skipped::StreamedBody => "streamed_body",
+            Skipped::Budget => "budget",
+            Skipped::RetryAfter(_) => "retry_after",
+            Skipped::Deadline => "deadline",
+        }
+    }
+
+    /// Why, for the logs
+    pub fn message(self, attempts: u32) -> String {
+        match self {
+            Skipped::Attempts => format!("gave up after {} attempts", attempts),
+            Skipped::StreamedBody => "the request body was streamed, so it can't be sent again".to_string(),
+            Skipped::Budget => "the retry budget is spent".to_string(),
+            Skipped::RetryAfter(wait) => format!(
+                "the remote asked to retry after {}, longer than --retry-max-wait",
+                humantime::format_duration(wait)
+            ),
+            Skipped::Deadline => "the request would run out of time first".to_string(),
+        }
+    }
+}
+
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff: Duration,
+    max_wait: Duration,
+    budget_ratio: f64,
+    // Retries that can be made now
+    budget: Mutex<f64>,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, backoff: Duration, max_wait: Duration, budget_ratio: f64) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            backoff,
+            max_wait,
+            budget_ratio,
+            budget: Mutex::new(BUDGET_RESERVE),
+        }
+    }
+
+    /// Attempts allowed for a request, the route's setting ahead of the flag
+    pub fn max_attempts(&self, route: Option<u32>) -> u32 {
+        route.unwrap_or(self.max_attempts).max(1)
+    }
+
+    /// Adds a new request's share to the budget
+    pub fn request_started(&self) {
+        let mut budget = self.budget.lock().unwrap();
+        *budget = (*budget + self.budget_ratio).min(BUDGET_RESERVE);
+    }
+
+    /// How long to wait before the next attempt, after `attempt` attempts
+    /// have failed; takes the retry out of the budget
+    pub fn wait(&self, attempt: u32, retry_after: Option<Duration>) -> Result<Duration, Skipped> {
+        let wait = match retry_after {
+            Some(wait) if wait > self.max_wait => return Err(Skipped::RetryAfter(wait)),
+            Some(wait) => wait,
+            None => self.backoff(attempt),
+        };
+        let mut budget = self.budget.lock().unwrap();
+        if *budget < 1.0 {
+            return Err(Skipped::Budget);
+        }
+        *budget -= 1.0;
+        Ok(wait)
+    }
+
+    // Doubles with every attempt up to the longest wait, of which a random
+    // half is taken off so that retries from many clients spread out
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .backoff
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max_wait);
+        ceiling.mul_f64(rand::random_range(0.5..=1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(budget_ratio: f64) -> RetryPolicy {
+        RetryPolicy::new(3, Duration::from_secs(1), Duration::from_secs(10), budget_ratio)
+    }
+
+    #[test]
+    fn retries_429_only_with_retry_after() {
+        let later = Some(Duration::from_secs(1));
+        assert!(Failure::from_status(StatusCode::TOO_MANY_REQUESTS, later).is_some());
+        assert!(Failure::from_status(StatusCode::TOO_MANY_REQUESTS, None).is_none());
+        assert!(Failure::from_status(StatusCode::SERVICE_UNAVAILABLE, None).is_some());
+        assert!(Failure::from_status(StatusCode::BAD_GATEWAY, later).is_none());
+        assert!(Failure::from_status(StatusCode::INTERNAL_SERVER_ERROR, None).is_none());
+    }
+
+    #[test]
+    fn max_wait_caps_the_wait() {
+        let policy = policy(0.0);
+        assert_eq!(policy.wait(1, Some(Duration::from_secs(5))).unwrap(), Duration::from_secs(5));
+        assert!(matches!(
+            policy.wait(1, Some(Duration::from_secs(30))),
+            Err(Skipped::RetryAfter(wait)) if wait == Duration::from_secs(30)
+        ));
+        for attempt in [1, 2, 10, u32::MAX] {
+            let wait = policy.wait(attempt, None).unwrap();
+            assert!(wait <= Duration::from_secs(10), "{:?}", wait);
+        }
+        let wait = policy.wait(10, None).unwrap();
+        assert!(wait >= Duration::from_secs(5), "{:?}", wait);
+    }
+
+    #[test]
+    fn spent_budget_stops_retries() {
+        let policy = policy(0.25);
+        for _ in 0..10 {
+            policy.wait(1, None).unwrap();
+        }
+        assert!(matches!(policy.wait(1, None), Err(Skipped::Budget)));
+
+        // Four requests earn one retry
+        for _ in 0..4 {
+            policy.request_started();
+        }
+        policy.wait(1, None).unwrap();
+        assert!(matches!(policy.wait(1, None), Err(Skipped::Budget)));
+        // A relayed Retry-After doesn't count against it
+        assert!(matches!(policy.wait(1, Some(Duration::from_secs(60))), Err(Skipped::RetryAfter(_))));
+    }
+
+    #[test]
+    fn budget_saves_up_to_the_reserve() {
+        let policy = policy(1.0);
+        for _ in 0..1000 {
+            policy.request_started();
+        }
+        for _ in 0..10 {
+            policy.wait(1, None).unwrap();
+        }
+        assert!(matches!(policy.wait(1, None), Err(Skipped::Budget)));
+    }
+
+    #[test]
+    fn route_sets_its_own_attempts() {
+        let policy = policy(0.1);
+        assert_eq!(policy.max_attempts(None), 3);
+        assert_eq!(policy.max_attempts(Some(5)), 5);
+        assert_eq!(policy.max_attempts(Some(0)), 1);
+    }
+}
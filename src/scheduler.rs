@@ -0,0 +1,713 @@
+//! Request scheduling
+//!
+//! Caps how many generation requests run on the remote at once, in total
+//! (`--max-concurrency`) and per model (`[[models]]` in the config file).
+//! Requests over a cap wait in a queue per client. A freed slot goes to the
+//! waiting client that has been served least relative to its `weight`
+//! (start-time fair queueing), so a client with hundreds of queued requests
+//! cannot hold back one that sends a single request.
+//!
+//! Requests are `interactive` or `batch`, as set on their route or client;
+//! a client can also mark a request as batch with `X-Priority: batch`, but
+//! never promote one. Interactive requests are admitted first, and while
+//! both kinds are waiting every `--batch-every`th slot goes to batch work so
+//! that it still makes progress.
+//!
+//! A request that carries a deadline of its own waits no longer than that,
+//! and is turned away at once if the requests ahead of it are expected to
+//! take longer.
+//!
+//! ```toml
+//! [[models]]
+//! name = "llama3.1:70b"
+//! max_concurrency = 2
+//!
+//! [[clients]]
+//! name = "ci"
+//! key = "proxy-key-for-ci"
+//! weight = 0.5
+//! priority = "batch"
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::header::{HeaderMap, HeaderName};
+use prometheus::IntGauge;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::limiter::{AdaptiveLimit, Sample};
+use crate::metrics::Metrics;
+
+// Upper bound for an adaptive limit when --max-concurrency doesn't set one
+const ADAPTIVE_MAX: usize = 100;
+
+// How far the usual time a slot is held moves towards each new one
+const HOLD_SMOOTHING: f64 = 0.1;
+
+// Models past this many only count towards the overall hold time
+const MAX_HOLD_TIMES: usize = 100;
+
+/// Lets a client mark a request as batch work
+pub const X_PRIORITY: HeaderName = HeaderName::from_static("x-priority");
+
+/// Endpoints that run a model, and so are scheduled
+const SCHEDULED: &[&str] = &[
+    "/api/chat",
+    "/api/generate",
+    "/api/embed",
+    "/api/embeddings",
+    "/v1/chat/completions",
+    "/v1/completions",
+    "/v1/embeddings",
+];
+
+/// Whether requests on this path take a slot
+pub fn is_scheduled(path: &str) -> bool {
+    SCHEDULED.contains(&path.trim_end_matches('/'))
+}
+
+/// Priority class of a request
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    Interactive,
+    Batch,
+}
+
+impl Priority {
+    const ALL: [Priority; 2] = [Priority::Interactive, Priority::Batch];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::Interactive => "interactive",
+            Priority::Batch => "batch",
+        }
+    }
+
+    /// The class configured for a request, lowered to batch if the client asks
+    pub fn for_request(configured: Option<Priority>, headers: &HeaderMap) -> Priority {
+        let asked_for_batch = headers
+            .get(X_PRIORITY)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("batch"));
+        if asked_for_batch {
+            Priority::Batch
+        } else {
+            configured.unwrap_or_default()
+        }
+    }
+}
+
+pub struct SchedulerSettings {
+    /// Requests running at once across all models; 0 for no limit
+    pub max_concurrency: usize,
+    /// Requests running at once for particular models
+    pub model_limits: HashMap<String, usize>,
+    /// Share of freed slots each client gets while several are waiting [default: 1]
+    pub weights: HashMap<String, f64>,
+    /// Requests that can wait at once before new ones are refused
+    pub max_queue: usize,
+    /// How long a request waits for a slot before it is refused
+    pub queue_timeout: Duration,
+    /// While both classes are waiting, every this many slots one goes to batch work; 0 never
+    pub batch_every: usize,
+    /// Find the total limit from the remote's latency and errors, up to `max_concurrency`
+    pub adaptive: bool,
+    /// Lowest an adaptive limit goes
+    pub min_concurrency: usize,
+    /// Latency over the usual by this factor counts as overload
+    pub latency_tolerance: f64,
+}
+
+/// Why a request didn't get a slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+    QueueFull,
+    TimedOut,
+    /// The client's deadline would pass, or passed, before a slot came free
+    Deadline,
+}
+
+impl Rejected {
+    /// Label for metrics and logs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rejected::QueueFull => "queue_full",
+            Rejected::TimedOut => "queue_timeout",
+            Rejected::Deadline => "deadline",
+        }
+    }
+}
+
+struct Waiter {
+    id: u64,
+    request_id: String,
+    model: String,
+    enqueued: Instant,
+    grant: oneshot::Sender<Permit>,
+}
+
+// A client's waiting requests
+struct ClientQueue {
+    waiters: VecDeque<Waiter>,
+    // Virtual time at which the client's next request starts; lowest goes first
+    tag: f64,
+    weight: f64,
+}
+
+// The waiting requests of one priority class
+#[derive(Default)]
+struct ClassQueue {
+    clients: HashMap<String, ClientQueue>,
+    queued: usize,
+    virtual_time: f64,
+}
+
+impl ClassQueue {
+    // The client with the lowest tag that has a request that fits, the
+    // longest waiting request first on a tie
+    fn next(&self, fits: impl Fn(&str) -> bool) -> Option<(String, usize)> {
+        self.clients
+            .iter()
+            .filter_map(|(client, queue)| {
+                let index = queue.waiters.iter().position(|waiter| fits(&waiter.model))?;
+                Some((client, index, queue.tag, queue.waiters[index].id))
+            })
+            .min_by(|a, b| a.2.total_cmp(&b.2).then(a.3.cmp(&b.3)))
+            .map(|(client, index, _, _)| (client.clone(), index))
+    }
+
+    fn take(&mut self, client: &str, index: usize) -> Waiter {
+        let queue = self.clients.get_mut(client).unwrap();
+        let waiter = queue.waiters.remove(index).unwrap();
+        self.virtual_time = queue.tag;
+        queue.tag += 1.0 / queue.weight;
+        if queue.waiters.is_empty() {
+            self.clients.remove(client);
+        }
+        self.queued -= 1;
+        waiter
+    }
+}
+
+struct State {
+    running: usize,
+    running_by_model: HashMap<String, usize>,
+    // Indexed by priority class
+    classes: [ClassQueue; 2],
+    next_id: u64,
+    // Interactive requests admitted in a row while batch work could have run
+    interactive_streak: usize,
+    adaptive: Option<AdaptiveLimit>,
+    // Usual time from being given a slot to giving it back, in seconds,
+    // overall and by model
+    mean_hold: Option<f64>,
+    model_hold: HashMap<String, f64>,
+}
+
+impl State {
+    fn queued(&self) -> usize {
+        self.classes.iter().map(|class| class.queued).sum()
+    }
+}
+
+pub struct Scheduler {
+    settings: SchedulerSettings,
+    state: Mutex<State>,
+    // By priority class
+    queue_depth: [IntGauge; 2],
+}
+
+/// A slot on the remote, given back when dropped
+pub struct Permit {
+    scheduler: Arc<Scheduler>,
+    model: String,
+    sample: Sample,
+}
+
+impl Permit {
+    fn new(scheduler: &Arc<Scheduler>, model: String) -> Self {
+        Permit {
+            scheduler: scheduler.clone(),
+            model,
+            sample: Sample {
+                admitted: Instant::now(),
+                latency: None,
+                failed: false,
+            },
+        }
+    }
+
+    /// Records how the remote responded, for the adaptive limit
+    pub fn responded(&mut self, status: u16) {
+        self.sample.latency = Some(self.sample.admitted.elapsed());
+        self.sample.failed = status >= 500 || status == 429;
+    }
+
+    /// Records that the response broke off part way
+    pub fn failed(&mut self) {
+        self.sample.failed = true;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.scheduler.release(&self.model, &self.sample);
+    }
+}
+
+/// How a request fared on arrival
+pub enum Admission {
+    Admitted(Permit),
+    Queued(Ticket),
+}
+
+/// A request's place in the queue; dropping it leaves the queue
+pub struct Ticket {
+    scheduler: Arc<Scheduler>,
+    priority: Priority,
+    client: String,
+    id: u64,
+    granted: oneshot::Receiver<Permit>,
+    deadline: tokio::time::Instant,
+    // Whether the deadline is the queue timeout or the client's own
+    expires_with: Rejected,
+}
+
+impl Ticket {
+    /// Requests expected to get a slot before this one, plus one
+    pub fn position(&self) -> usize {
+        let state = self.scheduler.state.lock().unwrap();
+        position(&state, self.priority, &self.client, self.id).unwrap_or(1)
+    }
+
+    /// Waits for a slot until the queue timeout or the client's deadline
+    pub async fn wait(mut self) -> Result<Permit, Rejected> {
+        match tokio::time::timeout_at(self.deadline, &mut self.granted).await {
+            Ok(Ok(permit)) => Ok(permit),
+            // The sender is only dropped without a permit if the request left the queue
+            Ok(Err(_)) | Err(_) => Err(self.expires_with),
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut state = self.scheduler.state.lock().unwrap();
+        let class = &mut state.classes[self.priority as usize];
+        let Some(queue) = class.clients.get_mut(&self.client) else {
+            return;
+        };
+        let Some(index) = queue.waiters.iter().position(|waiter| waiter.id == self.id) else {
+            return;
+        };
+        queue.waiters.remove(index);
+        if queue.waiters.is_empty() {
+            class.clients.remove(&self.client);
+        }
+        class.queued -= 1;
+        self.scheduler.update_depth(&state);
+    }
+}
+
+/// Where a waiting request stands, for `/_agent/queue/{request_id}`
+#[derive(Serialize)]
+pub struct QueueStatus {
+    pub request_id: String,
+    pub priority: &'static str,
+    pub position: usize,
+    pub waiting_ms: u64,
+}
+
+// Estimates a waiter's place from the order fair queueing would serve the
+// queue in if nothing else arrived; ignores which models have room
+fn position(state: &State, priority: Priority, client: &str, id: u64) -> Option<usize> {
+    let class = &state.classes[priority as usize];
+    let queue = class.clients.get(client)?;
+    let index = queue.waiters.iter().position(|waiter| waiter.id == id)?;
+    let start = |queue: &ClientQueue, index: usize| queue.tag + index as f64 / queue.weight;
+    let own = (start(queue, index), id);
+
+    let mut ahead = 0;
+    for queue in class.clients.values() {
+        for (index, waiter) in queue.waiters.iter().enumerate() {
+            if (start(queue, index), waiter.id) < own {
+                ahead += 1;
+            }
+        }
+    }
+    if priority == Priority::Batch {
+        ahead += state.classes[Priority::Interactive as usize].queued;
+    }
+    Some(ahead + 1)
+}
+
+impl Scheduler {
+    pub fn new(settings: SchedulerSettings, metrics: &Metrics) -> Arc<Self> {
+        let adaptive = settings.adaptive.then(|| {
+            let max = match settings.max_concurrency {
+                0 => ADAPTIVE_MAX,
+                max => max,
+            };
+            AdaptiveLimit::new(
+                settings.min_concurrency,
+                max,
+                settings.latency_tolerance,
+                metrics.concurrency_limit(),
+            )
+        });
+        Arc::new(Scheduler {
+            settings,
+            state: Mutex::new(State {
+                running: 0,
+                running_by_model: HashMap::new(),
+                classes: Default::default(),
+                next_id: 0,
+                interactive_streak: 0,
+                adaptive,
+                mean_hold: None,
+                model_hold: HashMap::new(),
+            }),
+            queue_depth: Priority::ALL.map(|priority| metrics.queue_depth(priority.as_str())),
+        })
+    }
+
+    /// Whether any limit is configured at all
+    pub fn is_enabled(&self) -> bool {
+        self.settings.adaptive || self.settings.max_concurrency > 0 || !self.settings.model_limits.is_empty()
+    }
+
+    /// Gives a request from `client` for `model` a slot, or a place in the
+    /// queue if it can expect one before `deadline`
+    pub fn admit(
+        self: &Arc<Self>,
+        priority: Priority,
+        client: &str,
+        model: &str,
+        request_id: &str,
+        deadline: Option<tokio::time::Instant>,
+    ) -> Result<Admission, Rejected> {
+        let mut state = self.state.lock().unwrap();
+        // Every slot is handed out as soon as it frees up, so a request
+        // that fits now isn't jumping ahead of anyone who could use it
+        if self.has_room(&state, model) {
+            self.start(&mut state, model);
+            return Ok(Admission::Admitted(Permit::new(self, model.to_string())));
+        }
+        if state.queued() >= self.settings.max_queue {
+            return Err(Rejected::QueueFull);
+        }
+        let now = tokio::time::Instant::now();
+        if let Some(deadline) = deadline {
+            let expected = self.expected_wait(&state, priority, model).unwrap_or_default();
+            if now + expected >= deadline {
+                return Err(Rejected::Deadline);
+            }
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        let (grant, granted) = oneshot::channel();
+        let weight = self.settings.weights.get(client).copied().unwrap_or(1.0);
+        let class = &mut state.classes[priority as usize];
+        let virtual_time = class.virtual_time;
+        // A client that wasn't waiting starts level with the others rather than with saved-up credit
+        let queue = class
+            .clients
+            .entry(client.to_string())
+            .or_insert_with(|| ClientQueue {
+                waiters: VecDeque::new(),
+                tag: virtual_time,
+                weight,
+            });
+        queue.waiters.push_back(Waiter {
+            id,
+            request_id: request_id.to_string(),
+            model: model.to_string(),
+            enqueued: Instant::now(),
+            grant,
+        });
+        class.queued += 1;
+        self.update_depth(&state);
+
+        let queue_deadline = now + self.settings.queue_timeout;
+        let (deadline, expires_with) = match deadline {
+            Some(deadline) if deadline < queue_deadline => (deadline, Rejected::Deadline),
+            _ => (queue_deadline, Rejected::TimedOut),
+        };
+        Ok(Admission::Queued(Ticket {
+            scheduler: self.clone(),
+            priority,
+            client: client.to_string(),
+            id,
+            granted,
+            deadline,
+            expires_with,
+        }))
+    }
+
+    /// Where the request with this ID stands, if it is waiting
+    pub fn status(&self, request_id: &str) -> Option<QueueStatus> {
+        let state = self.state.lock().unwrap();
+        for priority in Priority::ALL {
+            for (client, queue) in &state.classes[priority as usize].clients {
+                if let Some(waiter) = queue.waiters.iter().find(|waiter| waiter.request_id == request_id) {
+                    return Some(QueueStatus {
+                        request_id: request_id.to_string(),
+                        priority: priority.as_str(),
+                        position: position(&state, priority, client, waiter.id)?,
+                        waiting_ms: waiter.enqueued.elapsed().as_millis() as u64,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    // Roughly how long a request joining the back of its class's queue waits:
+    // the requests ahead of it, each holding one of the slots it could use
+    // for the usual time. None until a slot has been given back.
+    fn expected_wait(&self, state: &State, priority: Priority, model: &str) -> Option<Duration> {
+        let mean_hold = state.model_hold.get(model).copied().or(state.mean_hold)?;
+        let ahead = match priority {
+            Priority::Interactive => state.classes[Priority::Interactive as usize].queued,
+            Priority::Batch => state.queued(),
+        };
+        let total = match &state.adaptive {
+            Some(adaptive) => Some(adaptive.limit()),
+            None => Some(self.settings.max_concurrency).filter(|limit| *limit > 0),
+        };
+        let slots = total
+            .into_iter()
+            .chain(self.settings.model_limits.get(model).copied())
+            .min()
+            .unwrap_or(1)
+            .max(1);
+        Some(Duration::from_secs_f64(mean_hold * (ahead + 1) as f64 / slots as f64))
+    }
+
+    fn has_room(&self, state: &State, model: &str) -> bool {
+        let total = match &state.adaptive {
+            Some(adaptive) => state.running < adaptive.limit(),
+            None => self.settings.max_concurrency == 0 || state.running < self.settings.max_concurrency,
+        };
+        let for_model = self.settings.model_limits.get(model).is_none_or(|limit| {
+            state.running_by_model.get(model).copied().unwrap_or(0) < *limit
+        });
+        total && for_model
+    }
+
+    fn start(&self, state: &mut State, model: &str) {
+        state.running += 1;
+        *state.running_by_model.entry(model.to_string()).or_insert(0) += 1;
+    }
+
+    fn release(self: &Arc<Self>, model: &str, sample: &Sample) {
+        let grants = {
+            let mut state = self.state.lock().unwrap();
+            let saturated = state.queued() > 0 || !self.has_room(&state, "");
+            if let Some(adaptive) = &mut state.adaptive {
+                adaptive.update(model, sample, saturated);
+            }
+            let held = sample.admitted.elapsed().as_secs_f64();
+            let smooth = |mean: f64| mean + (held - mean) * HOLD_SMOOTHING;
+            state.mean_hold = Some(state.mean_hold.map_or(held, smooth));
+            if state.model_hold.len() < MAX_HOLD_TIMES || state.model_hold.contains_key(model) {
+                let mean = state.model_hold.entry(model.to_string()).or_insert(held);
+                *mean = smooth(*mean);
+            }
+            state.running -= 1;
+            if let Some(running) = state.running_by_model.get_mut(model) {
+                *running -= 1;
+                if *running == 0 {
+                    state.running_by_model.remove(model);
+                }
+            }
+            self.dispatch(&mut state)
+        };
+        send(grants);
+    }
+
+    // Hands free slots to waiting requests, interactive work and the fairest
+    // client first. The permits are sent once the lock is released, as a
+    // permit that can't be delivered is dropped and so takes the lock to give
+    // its slot back.
+    fn dispatch(self: &Arc<Self>, state: &mut State) -> Vec<(oneshot::Sender<Permit>, Permit)> {
+        let mut grants = Vec::new();
+        loop {
+            let fits = |model: &str| self.has_room(state, model);
+            let interactive = state.classes[Priority::Interactive as usize].next(fits);
+            let batch = state.classes[Priority::Batch as usize].next(fits);
+            let (priority, (client, index)) = match (interactive, batch) {
+                (Some(next), None) => (Priority::Interactive, next),
+                (None, Some(next)) => (Priority::Batch, next),
+                (Some(interactive), Some(batch)) => {
+                    let batch_turn = self.settings.batch_every > 0
+                        && state.interactive_streak + 1 >= self.settings.batch_every;
+                    if batch_turn {
+                        (Priority::Batch, batch)
+                    } else {
+                        state.interactive_streak += 1;
+                        (Priority::Interactive, interactive)
+                    }
+                }
+                (None, None) => break,
+            };
+            if priority == Priority::Batch {
+                state.interactive_streak = 0;
+            }
+
+            let waiter = state.classes[priority as usize].take(&client, index);
+            self.start(state, &waiter.model);
+            grants.push((waiter.grant, Permit::new(self, waiter.model)));
+        }
+        self.update_depth(state);
+        grants
+    }
+
+    fn update_depth(&self, state: &State) {
+        for (gauge, class) in self.queue_depth.iter().zip(&state.classes) {
+            gauge.set(class.queued as i64);
+        }
+    }
+}
+
+fn send(grants: Vec<(oneshot::Sender<Permit>, Permit)>) {
+    for (grant, permit) in grants {
+        // A request that gave up waiting drops the permit, which frees the slot again
+        let _ = grant.send(permit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler(max_concurrency: usize, batch_every: usize, weights: &[(&str, f64)]) -> Arc<Scheduler> {
+        let metrics = Metrics::new("http://remote", Vec::new(), 10).unwrap();
+        let settings = SchedulerSettings {
+            max_concurrency,
+            model_limits: HashMap::new(),
+            weights: weights.iter().map(|(client, weight)| (client.to_string(), *weight)).collect(),
+            max_queue: 1000,
+            queue_timeout: Duration::from_secs(60),
+            batch_every,
+            adaptive: false,
+            min_concurrency: 1,
+            latency_tolerance: 2.0,
+        };
+        Scheduler::new(settings, &metrics)
+    }
+
+    fn admit(scheduler: &Arc<Scheduler>, priority: Priority, client: &str) -> Admission {
+        scheduler.admit(priority, client, "llama3", "request", None).ok().unwrap()
+    }
+
+    fn queue(scheduler: &Arc<Scheduler>, priority: Priority, client: &'static str) -> (&'static str, Ticket) {
+        match admit(scheduler, priority, client) {
+            Admission::Queued(ticket) => (client, ticket),
+            Admission::Admitted(_) => panic!("{} was admitted without queueing", client),
+        }
+    }
+
+    // Gives back the running slot and returns the client whose request got it
+    fn serve_next(running: &mut Option<Permit>, tickets: &mut Vec<(&'static str, Ticket)>) -> &'static str {
+        drop(running.take());
+        let index = tickets
+            .iter_mut()
+            .position(|(_, ticket)| match ticket.granted.try_recv() {
+                Ok(permit) => {
+                    *running = Some(permit);
+                    true
+                }
+                Err(_) => false,
+            })
+            .expect("a waiting request got the slot");
+        tickets.remove(index).0
+    }
+
+    fn hold_the_slot(scheduler: &Arc<Scheduler>) -> Option<Permit> {
+        match admit(scheduler, Priority::Interactive, "first") {
+            Admission::Admitted(permit) => Some(permit),
+            Admission::Queued(_) => panic!("the first request was queued"),
+        }
+    }
+
+    #[test]
+    fn a_heavy_client_cannot_starve_a_light_one() {
+        let scheduler = scheduler(1, 0, &[]);
+        let mut running = hold_the_slot(&scheduler);
+        let mut tickets: Vec<_> = (0..10).map(|_| queue(&scheduler, Priority::Interactive, "heavy")).collect();
+        tickets.push(queue(&scheduler, Priority::Interactive, "light"));
+        assert_eq!(tickets.last().unwrap().1.position(), 2);
+
+        let order: Vec<_> = (0..11).map(|_| serve_next(&mut running, &mut tickets)).collect();
+        assert_eq!(&order[..3], ["heavy", "light", "heavy"]);
+        assert!(tickets.is_empty());
+    }
+
+    #[test]
+    fn weights_share_out_the_slots() {
+        let scheduler = scheduler(1, 0, &[("big", 2.0)]);
+        let mut running = hold_the_slot(&scheduler);
+        let mut tickets = Vec::new();
+        for _ in 0..6 {
+            tickets.push(queue(&scheduler, Priority::Interactive, "big"));
+            tickets.push(queue(&scheduler, Priority::Interactive, "small"));
+        }
+        let order: Vec<_> = (0..6).map(|_| serve_next(&mut running, &mut tickets)).collect();
+        assert_eq!(order.iter().filter(|client| **client == "big").count(), 4);
+    }
+
+    #[test]
+    fn virtual_time_advances_as_requests_are_served() {
+        let scheduler = scheduler(1, 0, &[]);
+        let mut running = hold_the_slot(&scheduler);
+        let mut tickets: Vec<_> = (0..3).map(|_| queue(&scheduler, Priority::Interactive, "heavy")).collect();
+        let virtual_time = || scheduler.state.lock().unwrap().classes[Priority::Interactive as usize].virtual_time;
+
+        assert_eq!(serve_next(&mut running, &mut tickets), "heavy");
+        assert_eq!(virtual_time(), 0.0);
+        assert_eq!(serve_next(&mut running, &mut tickets), "heavy");
+        assert_eq!(virtual_time(), 1.0);
+
+        // A client that wasn't waiting starts at the current virtual time, not with credit saved from idling
+        tickets.push(queue(&scheduler, Priority::Interactive, "late"));
+        let tag = scheduler.state.lock().unwrap().classes[Priority::Interactive as usize].clients["late"].tag;
+        assert_eq!(tag, 1.0);
+        assert_eq!(serve_next(&mut running, &mut tickets), "late");
+        assert_eq!(serve_next(&mut running, &mut tickets), "heavy");
+        assert_eq!(virtual_time(), 2.0);
+    }
+
+    #[test]
+    fn batch_work_gets_through_a_stream_of_interactive_requests() {
+        let scheduler = scheduler(1, 3, &[]);
+        let mut running = hold_the_slot(&scheduler);
+        let mut tickets = vec![queue(&scheduler, Priority::Batch, "nightly")];
+        // A new interactive request turns up each time a slot frees
+        let mut order = Vec::new();
+        for _ in 0..6 {
+            tickets.push(queue(&scheduler, Priority::Interactive, "chat"));
+            order.push(serve_next(&mut running, &mut tickets));
+        }
+        assert_eq!(order, ["chat", "chat", "nightly", "chat", "chat", "chat"]);
+    }
+
+    #[test]
+    fn batch_work_waits_for_interactive_without_batch_every() {
+        let scheduler = scheduler(1, 0, &[]);
+        let mut running = hold_the_slot(&scheduler);
+        let mut tickets = vec![queue(&scheduler, Priority::Batch, "nightly")];
+        for _ in 0..5 {
+            tickets.push(queue(&scheduler, Priority::Interactive, "chat"));
+            assert_eq!(serve_next(&mut running, &mut tickets), "chat");
+        }
+        assert_eq!(tickets[0].1.position(), 1);
+        assert_eq!(serve_next(&mut running, &mut tickets), "nightly");
+    }
+}
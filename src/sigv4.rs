@@ -0,0 +1,525 @@
+//! AWS Signature Version 4 request signing
+//!
+//! Used when the remote sits behind an AWS API Gateway with IAM
+//! authorization. Credentials come from the standard environment variables
+//! or from a profile in the shared credentials file.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use hyper::header::{HeaderName, HeaderValue, HOST};
+use hyper::{Body, HeaderMap, Method, Request, Uri};
+use sha2::{Digest, Sha256};
+
+use crate::body::{self, ProxyBody};
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// How the request body is covered by the signature
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadSigning {
+    /// Buffer the request body and sign its SHA-256 hash
+    Buffered,
+    /// Sign with `UNSIGNED-PAYLOAD` and stream the body untouched
+    Unsigned,
+}
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl Credentials {
+    /// Loads credentials from `AWS_ACCESS_KEY_ID`/`AWS_SECRET_ACCESS_KEY`, falling
+    /// back to the named profile in the shared credentials file
+    pub fn load(profile: Option<&str>) -> Result<Self> {
+        if profile.is_none() {
+            if let (Ok(access_key_id), Ok(secret_access_key)) = (
+                std::env::var("AWS_ACCESS_KEY_ID"),
+                std::env::var("AWS_SECRET_ACCESS_KEY"),
+            ) {
+                return Ok(Self {
+                    access_key_id,
+                    secret_access_key,
+                    session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+                });
+            }
+        }
+
+        let profile = profile
+            .map(str::to_string)
+            .or_else(|| std::env::var("AWS_PROFILE").ok())
+            .unwrap_or_else(|| "default".to_string());
+        let path = credentials_file().context("Cannot locate the AWS credentials file")?;
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        let section = parse_profile(&contents, &profile).ok_or_else(|| {
+            anyhow!("Profile '{}' not found in {}", profile, path.display())
+        })?;
+        let field = |name: &str| {
+            section
+                .get(name)
+                .cloned()
+                .ok_or_else(|| anyhow!("Profile '{}' has no {}", profile, name))
+        };
+
+        Ok(Self {
+            access_key_id: field("aws_access_key_id")?,
+            secret_access_key: field("aws_secret_access_key")?,
+            session_token: section.get("aws_session_token").cloned(),
+        })
+    }
+}
+
+fn credentials_file() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("AWS_SHARED_CREDENTIALS_FILE") {
+        return Some(PathBuf::from(path));
+    }
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".aws").join("credentials"))
+}
+
+// Minimal INI reader for the `[profile]` sections of the credentials file
+fn parse_profile(contents: &str, profile: &str) -> Option<HashMap<String, String>> {
+    let mut current: Option<&str> = None;
+    let mut found = None;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let name = name.trim();
+            current = Some(name.strip_prefix("profile ").unwrap_or(name).trim());
+            if current == Some(profile) {
+                found.get_or_insert_with(HashMap::new);
+            }
+            continue;
+        }
+        if current == Some(profile) {
+            if let (Some(section), Some((key, value))) = (found.as_mut(), line.split_once('=')) {
+                section.insert(key.trim().to_lowercase(), value.trim().to_string());
+            }
+        }
+    }
+    found
+}
+
+pub struct SigV4Signer {
+    credentials: Credentials,
+    region: String,
+    service: String,
+    payload: PayloadSigning,
+}
+
+impl SigV4Signer {
+    pub fn new(credentials: Credentials, region: String, service: String, payload: PayloadSigning) -> Self {
+        Self {
+            credentials,
+            region,
+            service,
+            payload,
+        }
+    }
+
+    /// Short description for the startup log, never containing secrets
+    pub fn describe(&self) -> String {
+        format!(
+            "aws sigv4 ({}/{}, key id {}, {} payload)",
+            self.region,
+            self.service,
+            self.credentials.access_key_id,
+            match self.payload {
+                PayloadSigning::Buffered => "signed",
+                PayloadSigning::Unsigned => "unsigned",
+            }
+        )
+    }
+
+    /// Signs an outgoing request, buffering the body when the payload is signed
+    pub async fn sign_request(&self, req: Request<ProxyBody>) -> Result<Request<ProxyBody>> {
+        let (mut parts, body) = req.into_parts();
+
+        let (body, payload_hash) = match self.payload {
+            PayloadSigning::Buffered => {
+                let bytes = hyper::body::to_bytes(body)
+                    .await
+                    .map_err(|err| anyhow!("Failed to read request body for signing: {}", err))?;
+                let hash = hex::encode(Sha256::digest(&bytes));
+                (body::boxed(Body::from(bytes)), hash)
+            }
+            PayloadSigning::Unsigned => {
+                // The remote has to be told explicitly that the payload is not signed
+                parts.headers.insert(
+                    HeaderName::from_static("x-amz-content-sha256"),
+                    HeaderValue::from_static(UNSIGNED_PAYLOAD),
+                );
+                (body, UNSIGNED_PAYLOAD.to_string())
+            }
+        };
+
+        // Pin the Host header so that what we sign is what hyper sends
+        let host = host_header(&parts.uri).ok_or_else(|| anyhow!("Request URI has no host"))?;
+        parts.headers.insert(HOST, HeaderValue::from_str(&host)?);
+
+        self.sign_parts(
+            &parts.method,
+            parts.uri.path(),
+            parts.uri.query().unwrap_or(""),
+            &mut parts.headers,
+            &payload_hash,
+            SystemTime::now(),
+        )?;
+        Ok(Request::from_parts(parts, body))
+    }
+
+    /// Adds `X-Amz-Date`, the session token and `Authorization` to the headers of
+    /// a request for `path` and `query` as they are sent
+    fn sign_parts(
+        &self,
+        method: &Method,
+        path: &str,
+        query: &str,
+        headers: &mut HeaderMap,
+        payload_hash: &str,
+        now: SystemTime,
+    ) -> Result<()> {
+        let amz_date = amz_date(now);
+        let date = &amz_date[..8];
+
+        headers.insert(
+            HeaderName::from_static("x-amz-date"),
+            HeaderValue::from_str(&amz_date)?,
+        );
+        if let Some(token) = &self.credentials.session_token {
+            headers.insert(
+                HeaderName::from_static("x-amz-security-token"),
+                HeaderValue::from_str(token)?,
+            );
+        }
+
+        let (canonical_request, signed_headers) = canonical_request(method, path, query, headers, payload_hash);
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, self.service);
+        let string_to_sign = string_to_sign(&amz_date, &scope, &canonical_request);
+
+        let k_date = hmac(
+            format!("AWS4{}", self.credentials.secret_access_key).as_bytes(),
+            date.as_bytes(),
+        );
+        let k_region = hmac(&k_date, self.region.as_bytes());
+        let k_service = hmac(&k_region, self.service.as_bytes());
+        let k_signing = hmac(&k_service, b"aws4_request");
+        let signature = hex::encode(hmac(&k_signing, string_to_sign.as_bytes()));
+
+        let authorization = format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM, self.credentials.access_key_id, scope, signed_headers, signature
+        );
+        headers.insert(
+            hyper::header::AUTHORIZATION,
+            HeaderValue::from_str(&authorization)?,
+        );
+        Ok(())
+    }
+}
+
+// The canonical request and its list of signed headers
+fn canonical_request(
+    method: &Method,
+    path: &str,
+    query: &str,
+    headers: &HeaderMap,
+    payload_hash: &str,
+) -> (String, String) {
+    // Only sign the headers the remote relies on; others may be rewritten in transit
+    let mut signed: Vec<(String, String)> = headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            name == "host" || name == "content-type" || name.starts_with("x-amz-")
+        })
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            (
+                name.as_str().to_string(),
+                value.split_whitespace().collect::<Vec<_>>().join(" "),
+            )
+        })
+        .collect();
+    signed.sort();
+
+    let mut canonical_headers = String::new();
+    for (name, value) in &signed {
+        canonical_headers.push_str(&format!("{}:{}\n", name, value));
+    }
+    let signed_headers = signed
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method.as_str(),
+        uri_encode(path, false),
+        canonical_query(query),
+        canonical_headers,
+        signed_headers,
+        payload_hash
+    );
+    (canonical_request, signed_headers)
+}
+
+fn string_to_sign(amz_date: &str, scope: &str, canonical_request: &str) -> String {
+    format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    )
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Host header as hyper would send it: the port only when it is not the scheme default
+fn host_header(uri: &Uri) -> Option<String> {
+    let host = uri.host()?;
+    let default_port = match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    };
+    Some(match uri.port_u16() {
+        Some(port) if port != default_port => format!("{}:{}", host, port),
+        _ => host.to_string(),
+    })
+}
+
+// Formats a timestamp as `YYYYMMDDTHHMMSSZ`
+fn amz_date(now: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(now)
+        .to_string()
+        .replace(['-', ':'], "")
+}
+
+// Percent-encodes everything except the unreserved characters, optionally keeping `/`
+fn uri_encode(input: &str, encode_slash: bool) -> String {
+    let mut out = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char)
+            }
+            b'/' if !encode_slash => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        // from_str_radix would also take a sign, as in `%+1`
+        if bytes[i] == b'%' && i + 2 < bytes.len() && bytes[i + 1..i + 3].iter().all(u8::is_ascii_hexdigit) {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+            out.push(u8::from_str_radix(hex, 16).unwrap());
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Sorts the query parameters and re-encodes them the way AWS expects
+fn canonical_query(query: &str) -> String {
+    let mut params: Vec<(String, String)> = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (key, value) = p.split_once('=').unwrap_or((p, ""));
+            (
+                uri_encode(&percent_decode(key), true),
+                uri_encode(&percent_decode(value), true),
+            )
+        })
+        .collect();
+    params.sort();
+    params
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // Requests, credentials and expected results from the AWS SigV4 test suite
+    // (aws-sig-v4-test-suite), signed at 20150830T123600Z
+    const SCOPE: &str = "20150830/us-east-1/service/aws4_request";
+
+    fn signer() -> SigV4Signer {
+        let credentials = Credentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        SigV4Signer::new(credentials, "us-east-1".to_string(), "service".to_string(), PayloadSigning::Buffered)
+    }
+
+    struct Expected<'a> {
+        canonical_request: &'a str,
+        string_to_sign: &'a str,
+        signed_headers: &'a str,
+        signature: &'a str,
+    }
+
+    fn check(method: Method, path: &str, headers: &[(&str, &str)], payload_hash: &str, expected: Expected) {
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        let mut map = HeaderMap::new();
+        map.insert(HOST, HeaderValue::from_static("example.amazonaws.com"));
+        for (name, value) in headers {
+            map.insert(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        let now = UNIX_EPOCH + Duration::from_secs(1_440_938_160);
+        signer().sign_parts(&method, path, query, &mut map, payload_hash, now).unwrap();
+
+        assert_eq!(map["x-amz-date"], "20150830T123600Z");
+        let (canonical_request, signed_headers) = canonical_request(&method, path, query, &map, payload_hash);
+        assert_eq!(canonical_request, expected.canonical_request);
+        assert_eq!(signed_headers, expected.signed_headers);
+        assert_eq!(
+            string_to_sign("20150830T123600Z", SCOPE, &canonical_request),
+            expected.string_to_sign
+        );
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/{}, SignedHeaders={}, Signature={}",
+            SCOPE, expected.signed_headers, expected.signature
+        );
+        assert_eq!(map[hyper::header::AUTHORIZATION], authorization.as_str());
+    }
+
+    fn body_hash(body: &[u8]) -> String {
+        hex::encode(Sha256::digest(body))
+    }
+
+    #[test]
+    fn get_vanilla() {
+        check(
+            Method::GET,
+            "/",
+            &[],
+            &body_hash(b""),
+            Expected {
+                canonical_request: "GET\n/\n\nhost:example.amazonaws.com\nx-amz-date:20150830T123600Z\n\n\
+                                    host;x-amz-date\n\
+                                    e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                string_to_sign: "AWS4-HMAC-SHA256\n20150830T123600Z\n20150830/us-east-1/service/aws4_request\n\
+                                 bb579772317eb040ac9ed261061d46c1f17a8133879d6129b6e1c25292927e63",
+                signed_headers: "host;x-amz-date",
+                signature: "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+            },
+        );
+    }
+
+    #[test]
+    fn get_vanilla_query_order_key_case() {
+        check(
+            Method::GET,
+            "/?Param2=value2&Param1=value1",
+            &[],
+            &body_hash(b""),
+            Expected {
+                canonical_request: "GET\n/\nParam1=value1&Param2=value2\nhost:example.amazonaws.com\n\
+                                    x-amz-date:20150830T123600Z\n\nhost;x-amz-date\n\
+                                    e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                string_to_sign: "AWS4-HMAC-SHA256\n20150830T123600Z\n20150830/us-east-1/service/aws4_request\n\
+                                 816cd5b414d056048ba4f7c5386d6e0533120fb1fcfa93762cf0fc39e2cf19e0",
+                signed_headers: "host;x-amz-date",
+                signature: "b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500",
+            },
+        );
+    }
+
+    #[test]
+    fn get_utf8() {
+        check(
+            Method::GET,
+            "/ሴ",
+            &[],
+            &body_hash(b""),
+            Expected {
+                canonical_request: "GET\n/%E1%88%B4\n\nhost:example.amazonaws.com\nx-amz-date:20150830T123600Z\n\n\
+                                    host;x-amz-date\n\
+                                    e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                string_to_sign: "AWS4-HMAC-SHA256\n20150830T123600Z\n20150830/us-east-1/service/aws4_request\n\
+                                 2a0a97d02205e45ce2e994789806b19270cfbbb0921b278ccf58f5249ac42102",
+                signed_headers: "host;x-amz-date",
+                signature: "8318018e0b0f223aa2bbf98705b62bb787dc9c0e678f255a891fd03141be5d85",
+            },
+        );
+    }
+
+    #[test]
+    fn post_x_www_form_urlencoded() {
+        check(
+            Method::POST,
+            "/",
+            &[("content-type", "application/x-www-form-urlencoded")],
+            &body_hash(b"Param1=value1"),
+            Expected {
+                canonical_request: "POST\n/\n\ncontent-type:application/x-www-form-urlencoded\n\
+                                    host:example.amazonaws.com\nx-amz-date:20150830T123600Z\n\n\
+                                    content-type;host;x-amz-date\n\
+                                    9095672bbd1f56dfc5b65f3e153adc8731a4a654192329106275f4c7b24d0b6e",
+                string_to_sign: "AWS4-HMAC-SHA256\n20150830T123600Z\n20150830/us-east-1/service/aws4_request\n\
+                                 42a5e5bb34198acb3e84da4f085bb7927f2bc277ca766e6d19c73c2154021281",
+                signed_headers: "content-type;host;x-amz-date",
+                signature: "ff11897932ad3f4e8b18135d722051e5ac45fc38421b1da7b9d196a0fe09473a",
+            },
+        );
+    }
+
+    #[test]
+    fn query_keeps_malformed_escapes() {
+        assert_eq!(canonical_query("a=%+1&b=%2B%zz"), "a=%25%2B1&b=%2B%25zz");
+    }
+
+    // Not part of the suite; worked out by hand the same way
+    #[test]
+    fn unsigned_payload() {
+        check(
+            Method::POST,
+            "/api/chat",
+            &[("x-amz-content-sha256", UNSIGNED_PAYLOAD)],
+            UNSIGNED_PAYLOAD,
+            Expected {
+                canonical_request: "POST\n/api/chat\n\nhost:example.amazonaws.com\n\
+                                    x-amz-content-sha256:UNSIGNED-PAYLOAD\nx-amz-date:20150830T123600Z\n\n\
+                                    host;x-amz-content-sha256;x-amz-date\nUNSIGNED-PAYLOAD",
+                string_to_sign: "AWS4-HMAC-SHA256\n20150830T123600Z\n20150830/us-east-1/service/aws4_request\n\
+                                 4bc7ef84f2f2640cfdc020f6a5cb1b818b869965e4ae492d0404faec43f00cf9",
+                signed_headers: "host;x-amz-content-sha256;x-amz-date",
+                signature: "f7285398d86577fc7f0e8e97be7cb9b2fe373917f74a9f45d7b79bf82872400b",
+            },
+        );
+    }
+}
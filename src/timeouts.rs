@@ -0,0 +1,569 @@
+//! Remote timeouts
+//!
+//! Four limits apply to every request sent to the remote: `connect` to open
+//! a connection, `first_byte` from sending the request to the remote's
+//! response headers, `idle` between chunks of a streamed response, and
+//! `total` for the whole exchange. The command-line flags set the defaults,
+//! a route's `timeouts` override them, and a model's override the route's:
+//!
+//! ```toml
+//! [[routes]]
+//! prefix = "/api/generate"
+//! timeouts = { idle = "2m", total = "30m" }
+//!
+//! [[models]]
+//! name = "llama3.1:405b"
+//! timeouts = { first_byte = "15m" }
+//! ```
+//!
+//! A client can also set a deadline with `X-Request-Timeout` (seconds, or a
+//! duration such as "20s") or gRPC's `grpc-timeout` ("20S", "500m"),
+//! counted from when the request arrived. The tighter of it and the total
+//! timeout applies, and the time left is passed on to the remote in the
+//! same header.
+//!
+//! A timeout before the response has started gets a `504` with an
+//! Ollama-style error. Once a stream is under way its status has gone out,
+//! so an NDJSON stream ends with an `{"error": ...}` line and a server-sent
+//! event stream with a `data:` event carrying one, the way Ollama and its
+//! OpenAI-compatible API report errors part way through. A body of known
+//! length has no room for an error, so it is cut off instead.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::Future;
+use http_body::SizeHint;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, Response};
+
+use crate::body::{self, BoxError, ProxyBody};
+use serde::{Deserialize, Deserializer};
+use tokio::time::{Instant, Sleep};
+
+/// Which timeout ran out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Connect,
+    FirstByte,
+    Idle,
+    Total,
+    /// The client's own deadline
+    Deadline,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 5] = [Phase::Connect, Phase::FirstByte, Phase::Idle, Phase::Total, Phase::Deadline];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::Connect => "connect",
+            Phase::FirstByte => "first_byte",
+            Phase::Idle => "idle",
+            Phase::Total => "total",
+            Phase::Deadline => "deadline",
+        }
+    }
+
+    /// The error reported to the client
+    pub fn message(self, limit: Duration) -> String {
+        let limit = humantime::format_duration(limit);
+        match self {
+            Phase::Connect => format!("timed out connecting to the remote after {}", limit),
+            Phase::FirstByte => format!("the remote did not start responding within {}", limit),
+            Phase::Idle => format!("the remote sent nothing for {}", limit),
+            Phase::Total => format!("the request took longer than {}", limit),
+            Phase::Deadline => format!("the request's deadline of {} passed", limit),
+        }
+    }
+}
+
+/// Timeouts set on a route or model, as durations such as "90s" or "10m"; "0s" means no limit
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutConfig {
+    #[serde(default, deserialize_with = "duration")]
+    pub connect: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    pub first_byte: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    pub idle: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    pub total: Option<Duration>,
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    humantime::parse_duration(&text)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// The timeouts for one request; `None` means no limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    pub first_byte: Option<Duration>,
+    pub idle: Option<Duration>,
+    pub total: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Timeouts in seconds, as given on the command line; 0 for no limit
+    pub fn from_secs(connect: u64, first_byte: u64, idle: u64, total: u64) -> Self {
+        let limit = |secs| Some(Duration::from_secs(secs)).filter(|limit| !limit.is_zero());
+        Timeouts {
+            connect: limit(connect),
+            first_byte: limit(first_byte),
+            idle: limit(idle),
+            total: limit(total),
+        }
+    }
+
+    /// These timeouts with the ones set in `config` taking their place
+    pub fn with(self, config: Option<&TimeoutConfig>) -> Self {
+        let Some(config) = config else {
+            return self;
+        };
+        let pick = |set: Option<Duration>, default| match set {
+            Some(limit) => Some(limit).filter(|limit| !limit.is_zero()),
+            None => default,
+        };
+        Timeouts {
+            connect: pick(config.connect, self.connect),
+            first_byte: pick(config.first_byte, self.first_byte),
+            idle: pick(config.idle, self.idle),
+            total: pick(config.total, self.total),
+        }
+    }
+}
+
+/// When a request must be finished by, and the timeout that set it
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    pub phase: Phase,
+    pub at: Instant,
+    pub limit: Duration,
+}
+
+impl Deadline {
+    pub fn after(phase: Phase, limit: Duration) -> Self {
+        Deadline {
+            phase,
+            at: Instant::now() + limit,
+            limit,
+        }
+    }
+
+    /// Whichever of two deadlines comes first
+    pub fn earliest(a: Option<Deadline>, b: Option<Deadline>) -> Option<Deadline> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(if b.at < a.at { b } else { a }),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+pub const X_REQUEST_TIMEOUT: HeaderName = HeaderName::from_static("x-request-timeout");
+pub const GRPC_TIMEOUT: HeaderName = HeaderName::from_static("grpc-timeout");
+
+// Longest deadline a client can set; anything longer is cut down to it
+const MAX_CLIENT_TIMEOUT: Duration = Duration::from_secs(24 * 3600);
+
+/// A deadline the client set, and the header it came in
+#[derive(Debug, Clone, Copy)]
+pub struct ClientDeadline {
+    pub deadline: Deadline,
+    grpc: bool,
+}
+
+impl ClientDeadline {
+    /// Takes the client's deadline out of the request headers, counting from
+    /// when the request arrived; a value that can't be parsed is dropped
+    pub fn take(headers: &mut HeaderMap, arrived: Instant) -> Option<Self> {
+        let timeout = headers.remove(X_REQUEST_TIMEOUT);
+        let grpc_timeout = headers.remove(GRPC_TIMEOUT);
+        let text = |value: &HeaderValue| value.to_str().ok().map(str::trim).map(str::to_string);
+        let (limit, grpc) = match (timeout.as_ref().and_then(text), grpc_timeout.as_ref().and_then(text)) {
+            (Some(timeout), _) => (parse_request_timeout(&timeout)?, false),
+            (None, Some(grpc_timeout)) => (parse_grpc_timeout(&grpc_timeout)?, true),
+            (None, None) => return None,
+        };
+        let limit = limit.min(MAX_CLIENT_TIMEOUT);
+        Some(ClientDeadline {
+            deadline: Deadline {
+                phase: Phase::Deadline,
+                at: arrived + limit,
+                limit,
+            },
+            grpc,
+        })
+    }
+
+    /// Tells the remote how long is left before `at`, in the header the client used
+    pub fn forward(&self, at: Instant, headers: &mut HeaderMap) {
+        let left = at.saturating_duration_since(Instant::now());
+        let value = if self.grpc {
+            // Eight digits at most; milliseconds cover over a day
+            format!("{}m", left.as_millis().min(99_999_999))
+        } else {
+            format!("{:.3}", left.as_secs_f64())
+        };
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            let name = if self.grpc { GRPC_TIMEOUT } else { X_REQUEST_TIMEOUT };
+            headers.insert(name, value);
+        }
+    }
+}
+
+// Seconds, possibly fractional, or a duration such as "20s"
+fn parse_request_timeout(text: &str) -> Option<Duration> {
+    match text.parse::<f64>() {
+        Ok(secs) => Duration::try_from_secs_f64(secs).ok(),
+        Err(_) => humantime::parse_duration(text).ok(),
+    }
+}
+
+// Up to eight digits and a unit: Hours, Minutes, Seconds, milliseconds, microseconds or nanoseconds
+fn parse_grpc_timeout(text: &str) -> Option<Duration> {
+    let (digits, unit) = text.split_at(text.len().checked_sub(1)?);
+    if digits.is_empty() || digits.len() > 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let value: u64 = digits.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(value * 3600)),
+        "M" => Some(Duration::from_secs(value * 60)),
+        "S" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_millis(value)),
+        "u" => Some(Duration::from_micros(value)),
+        "n" => Some(Duration::from_nanos(value)),
+        _ => None,
+    }
+}
+
+/// Set once a response body has run out of time; kept in the response's extensions
+#[derive(Clone, Default)]
+pub struct Expiry(Arc<Mutex<Option<Phase>>>);
+
+impl Expiry {
+    pub fn get(&self) -> Option<Phase> {
+        *self.0.lock().unwrap()
+    }
+}
+
+// How a stream can tell the client it was cut short
+#[derive(Clone, Copy)]
+enum ErrorFormat {
+    Ndjson,
+    EventStream,
+}
+
+/// Enforces the idle timeout and the deadline on a response body,
+/// leaving an `Expiry` in the response's extensions
+pub fn limit_body(
+    response: Response<Body>,
+    idle: Option<Duration>,
+    deadline: Option<Deadline>,
+) -> Response<ProxyBody> {
+    if idle.is_none() && deadline.is_none() {
+        return response.map(body::boxed);
+    }
+    let known_length = response.headers().contains_key(hyper::header::CONTENT_LENGTH);
+    let content_type = response
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    // An error line would run past the Content-Length
+    let format = if known_length {
+        None
+    } else if content_type.contains("ndjson") {
+        Some(ErrorFormat::Ndjson)
+    } else if content_type.contains("event-stream") {
+        Some(ErrorFormat::EventStream)
+    } else {
+        None
+    };
+
+    let expiry = Expiry::default();
+    let (mut parts, body) = response.into_parts();
+    parts.extensions.insert(expiry.clone());
+    let limited = Limited {
+        inner: body,
+        idle: idle.map(|idle| (idle, Box::pin(tokio::time::sleep(idle)))),
+        deadline: deadline.map(|deadline| (deadline, Box::pin(tokio::time::sleep_until(deadline.at)))),
+        format,
+        line_start: true,
+        expiry,
+        done: false,
+    };
+    Response::from_parts(parts, body::boxed(limited))
+}
+
+struct Limited {
+    inner: Body,
+    // Each timer with the limit it enforces
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+    deadline: Option<(Deadline, Pin<Box<Sleep>>)>,
+    format: Option<ErrorFormat>,
+    // Whether the last chunk ended a line, so an error line isn't glued onto it
+    line_start: bool,
+    expiry: Expiry,
+    done: bool,
+}
+
+impl Limited {
+    // Ends the body, with an error the client can parse where the format allows one
+    fn expire(&mut self, phase: Phase, limit: Duration) -> Option<Result<Bytes, BoxError>> {
+        *self.expiry.0.lock().unwrap() = Some(phase);
+        self.done = true;
+        let error = serde_json::json!({ "error": phase.message(limit) }).to_string();
+        let separator = if self.line_start { "" } else { "\n" };
+        match self.format {
+            Some(ErrorFormat::Ndjson) => Some(Ok(format!("{}{}\n", separator, error).into())),
+            Some(ErrorFormat::EventStream) => Some(Ok(format!("{}data: {}\n\n", separator, error).into())),
+            None => Some(Err(std::io::Error::new(std::io::ErrorKind::TimedOut, phase.message(limit)).into())),
+        }
+    }
+}
+
+impl HttpBody for Limited {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, BoxError>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        // The deadline is checked first so that a remote that never pauses can't outrun it
+        if let Some((deadline, timer)) = self.deadline.as_mut() {
+            if timer.as_mut().poll(cx).is_ready() {
+                let deadline = *deadline;
+                return Poll::Ready(self.expire(deadline.phase, deadline.limit));
+            }
+        }
+
+        match Pin::new(&mut self.inner).poll_data(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some((idle, timer)) = self.idle.as_mut() {
+                    timer.as_mut().reset(Instant::now() + *idle);
+                }
+                if let Some(last) = chunk.last() {
+                    self.line_start = *last == b'\n';
+                }
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Some(Err(err))) => {
+                self.done = true;
+                Poll::Ready(Some(Err(err.into())))
+            }
+            Poll::Ready(None) => {
+                self.done = true;
+                Poll::Ready(None)
+            }
+            Poll::Pending => {
+                if let Some((idle, timer)) = self.idle.as_mut() {
+                    if timer.as_mut().poll(cx).is_ready() {
+                        let idle = *idle;
+                        return Poll::Ready(self.expire(Phase::Idle, idle));
+                    }
+                }
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, BoxError>> {
+        // A body cut short has no trailers to send
+        if self.expiry.0.lock().unwrap().is_some() {
+            return Poll::Ready(Ok(None));
+        }
+        Pin::new(&mut self.inner).poll_trailers(cx).map_err(Into::into)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let inner = self.inner.size_hint();
+        match self.format {
+            // An error line added at expiry makes the body longer than the remote's
+            Some(_) => {
+                let mut hint = SizeHint::new();
+                hint.set_lower(inner.lower());
+                hint
+            }
+            None => inner,
+        }
+    }
+}
+
+/// Whether a failed remote request ran out of time opening the connection
+pub fn is_connect_timeout(err: &hyper::Error) -> bool {
+    let mut source = std::error::Error::source(err);
+    while let Some(err) = source {
+        if err
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|err| err.kind() == std::io::ErrorKind::TimedOut)
+        {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_timeout_takes_seconds_or_a_duration() {
+        assert_eq!(parse_request_timeout("20"), Some(Duration::from_secs(20)));
+        assert_eq!(parse_request_timeout("2.5"), Some(Duration::from_millis(2500)));
+        assert_eq!(parse_request_timeout("20s"), Some(Duration::from_secs(20)));
+        assert_eq!(parse_request_timeout("1m 30s"), Some(Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn request_timeout_rejects_what_isnt_a_duration() {
+        for text in ["-1", "NaN", "inf", "-inf", "1e400", "", "soon", "20 parsecs"] {
+            assert_eq!(parse_request_timeout(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn grpc_timeout_takes_every_unit() {
+        assert_eq!(parse_grpc_timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_grpc_timeout("2M"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_grpc_timeout("20S"), Some(Duration::from_secs(20)));
+        assert_eq!(parse_grpc_timeout("500m"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_grpc_timeout("7u"), Some(Duration::from_micros(7)));
+        assert_eq!(parse_grpc_timeout("9n"), Some(Duration::from_nanos(9)));
+        assert_eq!(parse_grpc_timeout("99999999H"), Some(Duration::from_secs(99_999_999 * 3600)));
+    }
+
+    #[test]
+    fn grpc_timeout_rejects_malformed_values() {
+        for text in ["", "S", "20", "20s", "20x", "-1S", "+1S", "1.5S", "123456789S", "NaNS", "infS"] {
+            assert_eq!(parse_grpc_timeout(text), None, "{:?}", text);
+        }
+    }
+
+    fn take(name: HeaderName, value: &str) -> Option<ClientDeadline> {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        ClientDeadline::take(&mut headers, Instant::now())
+    }
+
+    #[test]
+    fn a_huge_deadline_is_cut_down() {
+        let deadline = take(X_REQUEST_TIMEOUT, "18000000000000000000").unwrap();
+        assert_eq!(deadline.deadline.limit, MAX_CLIENT_TIMEOUT);
+        let deadline = take(GRPC_TIMEOUT, "99999999H").unwrap();
+        assert_eq!(deadline.deadline.limit, MAX_CLIENT_TIMEOUT);
+    }
+
+    #[test]
+    fn a_malformed_deadline_is_dropped() {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_REQUEST_TIMEOUT, HeaderValue::from_static("NaN"));
+        assert!(ClientDeadline::take(&mut headers, Instant::now()).is_none());
+        assert!(!headers.contains_key(X_REQUEST_TIMEOUT));
+        assert!(take(GRPC_TIMEOUT, "soon").is_none());
+    }
+
+    fn streamed(content_type: &str) -> (hyper::body::Sender, Response<Body>) {
+        let (sender, body) = Body::channel();
+        let response = Response::builder()
+            .header(hyper::header::CONTENT_TYPE, content_type)
+            .body(body)
+            .unwrap();
+        (sender, response)
+    }
+
+    fn expiry(response: &Response<ProxyBody>) -> Option<Phase> {
+        response.extensions().get::<Expiry>().unwrap().get()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_stream_ends_with_an_error_line() {
+        let (mut sender, response) = streamed("application/x-ndjson");
+        let mut response = limit_body(response, Some(Duration::from_secs(5)), None);
+        sender.send_data(Bytes::from_static(b"{\"done\":false}")).await.unwrap();
+
+        let started = Instant::now();
+        let body = response.body_mut();
+        assert_eq!(body.data().await.unwrap().unwrap(), "{\"done\":false}");
+        let error = body.data().await.unwrap().unwrap();
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+        assert!(error.starts_with(b"\n{\"error\":"), "{:?}", error);
+        assert!(error.ends_with(b"}\n"), "{:?}", error);
+        assert!(body.data().await.is_none());
+        assert_eq!(expiry(&response), Some(Phase::Idle));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn total_timeout_cuts_off_a_busy_stream() {
+        let (mut sender, response) = streamed("text/event-stream");
+        let total = Deadline::after(Phase::Total, Duration::from_secs(10));
+        let mut response = limit_body(response, Some(Duration::from_secs(5)), Some(total));
+        let started = Instant::now();
+        let feeder = tokio::spawn(async move {
+            loop {
+                if sender.send_data(Bytes::from_static(b"data: {}\n\n")).await.is_err() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs(3)).await;
+            }
+        });
+
+        let body = response.body_mut();
+        let mut last = Bytes::new();
+        while let Some(chunk) = body.data().await {
+            last = chunk.unwrap();
+        }
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+        assert!(last.starts_with(b"data: {\"error\":"), "{:?}", last);
+        assert_eq!(expiry(&response), Some(Phase::Total));
+        drop(response);
+        feeder.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline_cuts_off_a_sized_body() {
+        let (sender, body) = Body::channel();
+        let response = Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/x-ndjson")
+            .header(hyper::header::CONTENT_LENGTH, "100")
+            .body(body)
+            .unwrap();
+        let deadline = Deadline::after(Phase::Deadline, Duration::from_secs(2));
+        let mut response = limit_body(response, None, Some(deadline));
+
+        let started = Instant::now();
+        let err = response.body_mut().data().await.unwrap().unwrap_err();
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+        assert!(err.to_string().contains("deadline"), "{}", err);
+        assert_eq!(expiry(&response), Some(Phase::Deadline));
+        drop(sender);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn finished_body_is_not_expired() {
+        let (mut sender, response) = streamed("application/x-ndjson");
+        let mut response = limit_body(response, Some(Duration::from_secs(5)), None);
+        sender.send_data(Bytes::from_static(b"{}\n")).await.unwrap();
+        drop(sender);
+
+        assert_eq!(response.body_mut().data().await.unwrap().unwrap(), "{}\n");
+        assert!(response.body_mut().data().await.is_none());
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(expiry(&response), None);
+    }
+}
@@ -0,0 +1,494 @@
+//! Distributed tracing
+//!
+//! Each proxied request becomes a server span with three children that split
+//! up where the time went: the upstream request (connecting and waiting for
+//! response headers), the wait for the first body byte, and the stream to the
+//! client. Spans are exported in batches to an OpenTelemetry collector with
+//! OTLP/HTTP using the JSON encoding.
+//!
+//! A W3C `traceparent` header from the client is honoured: the request joins
+//! the client's trace and keeps its sampling decision. The remote gets a
+//! `traceparent` naming the upstream request span as its parent.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, HeaderMap, Method, Request};
+use log::{debug, warn};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::access_log::AccessRecord;
+use crate::usage::Usage;
+use crate::HttpClient;
+
+pub const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+
+// Spans waiting for export beyond this are dropped rather than held in memory
+const QUEUE_SIZE: usize = 4096;
+const BATCH_SIZE: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(2);
+
+// OTLP span kinds and status codes
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+const KIND_CLIENT: u8 = 3;
+const STATUS_ERROR: u8 = 2;
+
+/// Trace and span IDs for one request, and when its phases happened
+#[derive(Clone)]
+pub struct RequestTrace {
+    trace_id: [u8; 16],
+    parent_span_id: Option<[u8; 8]>,
+    sampled: bool,
+    span_id: [u8; 8],
+    upstream_span_id: [u8; 8],
+    start: SystemTime,
+    started: Instant,
+    upstream_sent: Option<Duration>,
+    upstream_done: Option<Duration>,
+    upstream_status: Option<u16>,
+    upstream_error: Option<String>,
+}
+
+impl RequestTrace {
+    /// Continues the client's trace if it sent a valid `traceparent`, otherwise starts one
+    pub fn from_headers(headers: &HeaderMap, started: Instant) -> Self {
+        let parent = headers
+            .get(TRACEPARENT)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_traceparent);
+        let (trace_id, parent_span_id, sampled) = match parent {
+            Some((trace_id, span_id, sampled)) => (trace_id, Some(span_id), sampled),
+            None => (rand::random(), None, true),
+        };
+
+        RequestTrace {
+            trace_id,
+            parent_span_id,
+            sampled,
+            span_id: rand::random(),
+            upstream_span_id: rand::random(),
+            start: SystemTime::now(),
+            started,
+            upstream_sent: None,
+            upstream_done: None,
+            upstream_status: None,
+            upstream_error: None,
+        }
+    }
+
+    /// `traceparent` to send upstream, with the upstream request span as parent
+    pub fn upstream_traceparent(&self) -> HeaderValue {
+        let value = format!(
+            "00-{}-{}-{}",
+            hex::encode(self.trace_id),
+            hex::encode(self.upstream_span_id),
+            if self.sampled { "01" } else { "00" }
+        );
+        HeaderValue::from_str(&value).expect("traceparent is a valid header")
+    }
+
+    /// Marks the first attempt at the remote; the upstream span covers any retries too
+    pub fn upstream_sent(&mut self) {
+        self.upstream_sent.get_or_insert_with(|| self.started.elapsed());
+    }
+
+    pub fn upstream_responded(&mut self, status: u16) {
+        self.upstream_done = Some(self.started.elapsed());
+        self.upstream_status = Some(status);
+        self.upstream_error = None;
+    }
+
+    pub fn upstream_failed(&mut self, error: String) {
+        self.upstream_done = Some(self.started.elapsed());
+        self.upstream_status = None;
+        self.upstream_error = Some(error);
+    }
+
+    fn at(&self, offset: Duration) -> SystemTime {
+        self.start + offset
+    }
+}
+
+// Parses `00-<trace id>-<parent id>-<flags>`, rejecting the all-zero IDs
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8], bool)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let (trace_id, span_id, flags) = (parts.next()?, parts.next()?, parts.next()?);
+    // Later versions may append fields, version 00 may not
+    if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    if trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+        return None;
+    }
+    // Only lowercase hex; the decoders below would also take uppercase and a sign
+    if ![version, trace_id, span_id, flags].iter().all(|field| is_lower_hex(field)) {
+        return None;
+    }
+
+    let trace_id: [u8; 16] = hex::decode(trace_id).ok()?.try_into().ok()?;
+    let span_id: [u8; 8] = hex::decode(span_id).ok()?.try_into().ok()?;
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    if trace_id == [0; 16] || span_id == [0; 8] {
+        return None;
+    }
+    Some((trace_id, span_id, flags & 1 == 1))
+}
+
+fn is_lower_hex(field: &str) -> bool {
+    field.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// When the response body went out, as offsets from the request's arrival
+pub struct ResponseTimes {
+    pub headers_sent: Duration,
+    pub first_byte: Option<Duration>,
+    pub end: Duration,
+}
+
+/// Sends finished spans to the collector; does nothing when tracing is off
+pub struct Tracer {
+    spans: Option<mpsc::Sender<Value>>,
+}
+
+impl Tracer {
+    pub fn disabled() -> Self {
+        Tracer { spans: None }
+    }
+
+    /// Starts the background exporter posting to `endpoint`
+    pub fn start(
+        endpoint: &str,
+        headers: &[String],
+        service_name: &str,
+        client: HttpClient,
+    ) -> Result<Self> {
+        // A bare collector address gets the standard traces path
+        let url = if endpoint.ends_with("/v1/traces") {
+            endpoint.to_string()
+        } else {
+            format!("{}/v1/traces", endpoint.trim_end_matches('/'))
+        };
+        url.parse::<hyper::Uri>()
+            .with_context(|| format!("Invalid OTLP endpoint {}", url))?;
+
+        let mut extra_headers = HeaderMap::new();
+        for header in headers {
+            let (name, value) = header
+                .split_once('=')
+                .with_context(|| format!("OTLP header '{}' is not name=value", header))?;
+            extra_headers.insert(
+                HeaderName::from_bytes(name.trim().as_bytes())?,
+                HeaderValue::from_str(value.trim())?,
+            );
+        }
+
+        let resource = json!({
+            "attributes": [
+                attribute("service.name", service_name),
+                attribute("service.version", env!("CARGO_PKG_VERSION")),
+            ]
+        });
+        let (spans, received) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(export_loop(received, url, extra_headers, resource, client));
+        Ok(Tracer { spans: Some(spans) })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.spans.is_some()
+    }
+
+    /// Builds and queues the spans of a finished request
+    pub fn finish(
+        &self,
+        trace: &RequestTrace,
+        record: &AccessRecord,
+        endpoint: &str,
+        times: &ResponseTimes,
+        usage: Option<&Usage>,
+    ) {
+        let Some(spans) = &self.spans else {
+            return;
+        };
+        if !trace.sampled {
+            return;
+        }
+
+        let mut attributes = vec![
+            attribute("http.request.method", record.method.as_str()),
+            attribute("url.path", record.path.as_str()),
+            attribute("http.response.status_code", record.status),
+            attribute("client.address", record.client_ip.as_str()),
+            attribute("ollama_agent.client", record.client.as_str()),
+            attribute("ollama_agent.request_id", record.request_id.as_str()),
+            attribute("ollama_agent.outcome", record.outcome),
+            attribute("http.request.body.size", record.bytes_in),
+            attribute("http.response.body.size", record.bytes_out),
+        ];
+        if let Some(model) = &record.model {
+            attributes.push(attribute("gen_ai.request.model", model.as_str()));
+        }
+        if let Some(usage) = usage {
+            attributes.push(attribute("gen_ai.usage.input_tokens", usage.prompt_eval_count));
+            attributes.push(attribute("gen_ai.usage.output_tokens", usage.eval_count));
+        }
+        let failed = record.status >= 500 || record.outcome == "failed";
+
+        let mut batch = vec![span(
+            trace,
+            trace.span_id,
+            trace.parent_span_id,
+            format!("{} {}", record.method, endpoint),
+            KIND_SERVER,
+            (trace.start, trace.at(times.end)),
+            attributes,
+            failed,
+        )];
+
+        if let Some(sent) = trace.upstream_sent {
+            let done = trace.upstream_done.unwrap_or(times.headers_sent);
+            let mut attributes = vec![attribute("server.address", record.upstream.as_str())];
+            if let Some(status) = trace.upstream_status {
+                attributes.push(attribute("http.response.status_code", status));
+            }
+            if let Some(error) = &trace.upstream_error {
+                attributes.push(attribute("error.message", error.as_str()));
+            }
+            if record.attempts > 1 {
+                attributes.push(attribute("http.request.resend_count", u64::from(record.attempts - 1)));
+            }
+            batch.push(span(
+                trace,
+                trace.upstream_span_id,
+                Some(trace.span_id),
+                "upstream request".to_string(),
+                KIND_CLIENT,
+                (trace.at(sent), trace.at(done)),
+                attributes,
+                trace.upstream_error.is_some() || trace.upstream_status.is_some_and(|s| s >= 500),
+            ));
+        }
+
+        if let Some(first_byte) = times.first_byte {
+            batch.push(span(
+                trace,
+                rand::random(),
+                Some(trace.span_id),
+                "first byte".to_string(),
+                KIND_INTERNAL,
+                (trace.at(times.headers_sent), trace.at(first_byte)),
+                Vec::new(),
+                false,
+            ));
+            batch.push(span(
+                trace,
+                rand::random(),
+                Some(trace.span_id),
+                "stream".to_string(),
+                KIND_INTERNAL,
+                (trace.at(first_byte), trace.at(times.end)),
+                vec![
+                    attribute("http.response.body.size", record.bytes_out),
+                    attribute("ollama_agent.streamed", record.streamed),
+                    attribute("ollama_agent.outcome", record.outcome),
+                ],
+                record.outcome == "failed",
+            ));
+        }
+
+        for span in batch {
+            if spans.try_send(span).is_err() {
+                debug!("Trace export queue is full, dropping a span");
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn span(
+    trace: &RequestTrace,
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: u8,
+    (start, end): (SystemTime, SystemTime),
+    attributes: Vec<Value>,
+    failed: bool,
+) -> Value {
+    let mut span = json!({
+        "traceId": hex::encode(trace.trace_id),
+        "spanId": hex::encode(span_id),
+        "name": name,
+        "kind": kind,
+        "startTimeUnixNano": unix_nanos(start).to_string(),
+        "endTimeUnixNano": unix_nanos(end).to_string(),
+        "attributes": attributes,
+    });
+    if let Some(parent) = parent_span_id {
+        span["parentSpanId"] = hex::encode(parent).into();
+    }
+    if failed {
+        span["status"] = json!({ "code": STATUS_ERROR });
+    }
+    span
+}
+
+fn attribute(key: &str, value: impl Into<AttributeValue>) -> Value {
+    let value = match value.into() {
+        AttributeValue::String(s) => json!({ "stringValue": s }),
+        // OTLP/JSON carries 64-bit integers as strings
+        AttributeValue::Int(i) => json!({ "intValue": i.to_string() }),
+        AttributeValue::Bool(b) => json!({ "boolValue": b }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+enum AttributeValue {
+    String(String),
+    Int(u64),
+    Bool(bool),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::String(value.to_string())
+    }
+}
+
+impl From<u64> for AttributeValue {
+    fn from(value: u64) -> Self {
+        AttributeValue::Int(value)
+    }
+}
+
+impl From<u16> for AttributeValue {
+    fn from(value: u16) -> Self {
+        AttributeValue::Int(value.into())
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(value: bool) -> Self {
+        AttributeValue::Bool(value)
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+// Collects spans and posts them when a batch fills up or the interval passes
+async fn export_loop(
+    mut received: mpsc::Receiver<Value>,
+    url: String,
+    headers: HeaderMap,
+    resource: Value,
+    client: HttpClient,
+) {
+    let mut batch = Vec::new();
+    let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+    loop {
+        let (flush, closed) = tokio::select! {
+            span = received.recv() => match span {
+                Some(span) => {
+                    batch.push(span);
+                    (batch.len() >= BATCH_SIZE, false)
+                }
+                None => (true, true),
+            },
+            _ = interval.tick() => (true, false),
+        };
+        if flush && !batch.is_empty() {
+            let spans = std::mem::take(&mut batch);
+            if let Err(e) = export(&url, &headers, &resource, spans, &client).await {
+                warn!("Failed to export spans to {}: {:#}", url, e);
+            }
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+async fn export(
+    url: &str,
+    headers: &HeaderMap,
+    resource: &Value,
+    spans: Vec<Value>,
+    client: &HttpClient,
+) -> Result<()> {
+    let count = spans.len();
+    let body = json!({
+        "resourceSpans": [{
+            "resource": resource,
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    });
+
+    let mut req = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(crate::body::boxed(Body::from(body.to_string())))?;
+    req.headers_mut().extend(headers.clone());
+
+    let resp = tokio::time::timeout(Duration::from_secs(10), client.request(req))
+        .await
+        .context("timed out")??;
+    if !resp.status().is_success() {
+        anyhow::bail!("collector answered {}", resp.status());
+    }
+    debug!("Exported {} spans", count);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN_ID: &str = "00f067aa0ba902b7";
+
+    #[test]
+    fn parses_a_traceparent() {
+        let (trace_id, span_id, sampled) = parse_traceparent(&format!("00-{}-{}-01", TRACE_ID, SPAN_ID)).unwrap();
+        assert_eq!(hex::encode(trace_id), TRACE_ID);
+        assert_eq!(hex::encode(span_id), SPAN_ID);
+        assert!(sampled);
+        let (_, _, sampled) = parse_traceparent(&format!("00-{}-{}-00", TRACE_ID, SPAN_ID)).unwrap();
+        assert!(!sampled);
+        // A later version may carry more fields
+        assert!(parse_traceparent(&format!("01-{}-{}-01-extra", TRACE_ID, SPAN_ID)).is_some());
+    }
+
+    #[test]
+    fn rejects_signs_and_uppercase() {
+        assert!(parse_traceparent(&format!("00-{}-{}-+1", TRACE_ID, SPAN_ID)).is_none());
+        assert!(parse_traceparent(&format!("00-{}-{}-01", TRACE_ID.to_uppercase(), SPAN_ID)).is_none());
+        assert!(parse_traceparent(&format!("00-{}-{}-01", TRACE_ID, SPAN_ID.to_uppercase())).is_none());
+        assert!(parse_traceparent(&format!("00-{}-{}-0A", TRACE_ID, SPAN_ID)).is_none());
+        assert!(parse_traceparent(&format!("+0-{}-{}-01", TRACE_ID, SPAN_ID)).is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_traceparents() {
+        for value in [
+            String::new(),
+            format!("00-{}-{}-01-extra", TRACE_ID, SPAN_ID),
+            format!("ff-{}-{}-01", TRACE_ID, SPAN_ID),
+            format!("00-{}-{}-01", &TRACE_ID[1..], SPAN_ID),
+            format!("00-{}-{}-01", "0".repeat(32), SPAN_ID),
+            format!("00-{}-{}-01", TRACE_ID, "0".repeat(16)),
+            format!("00-{}-{}", TRACE_ID, SPAN_ID),
+            format!("00-{}g-{}-01", &TRACE_ID[1..], SPAN_ID),
+        ] {
+            assert!(parse_traceparent(&value).is_none(), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Token usage reported by Ollama
+//!
+//! `/api/chat` and `/api/generate` end with a `"done": true` object that
+//! carries the token counts and timings of the generation. With streaming it
+//! is the last line of an NDJSON body; with `"stream": false` it is the whole
+//! body. [`UsageTap`] picks it out of the chunks as they are relayed, holding
+//! on to no more than the current line, and [`UsageTotals`] adds it up per
+//! client, model and upstream.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+// A final line longer than this (e.g. a huge `context` array) is given up on
+const MAX_LINE: usize = 4 * 1024 * 1024;
+
+/// Counts and durations from the final chunk; durations are in nanoseconds
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_eval_count: u64,
+    #[serde(default)]
+    pub eval_count: u64,
+    #[serde(default)]
+    pub total_duration: u64,
+    #[serde(default)]
+    pub load_duration: u64,
+    #[serde(default)]
+    pub prompt_eval_duration: u64,
+    #[serde(default)]
+    pub eval_duration: u64,
+}
+
+impl Usage {
+    /// Generation throughput as measured by the remote
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        (self.eval_duration > 0 && self.eval_count > 0)
+            .then(|| self.eval_count as f64 / (self.eval_duration as f64 / 1e9))
+    }
+}
+
+// The parts of a chunk that show whether it carries generated output
+#[derive(Deserialize)]
+struct OutputChunk {
+    #[serde(default)]
+    response: String,
+    #[serde(default)]
+    thinking: String,
+    message: Option<OutputMessage>,
+}
+
+#[derive(Deserialize)]
+struct OutputMessage {
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    thinking: String,
+    tool_calls: Option<serde_json::Value>,
+}
+
+impl OutputChunk {
+    fn has_output(&self) -> bool {
+        let message = self.message.as_ref().is_some_and(|message| {
+            !message.content.is_empty() || !message.thinking.is_empty() || message.tool_calls.is_some()
+        });
+        message || !self.response.is_empty() || !self.thinking.is_empty()
+    }
+}
+
+#[derive(Deserialize)]
+struct FinalChunk {
+    #[serde(default)]
+    done: bool,
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+/// Whether responses on this path end with a usage chunk
+pub fn reports_usage(path: &str) -> bool {
+    matches!(path.trim_end_matches('/'), "/api/chat" | "/api/generate")
+}
+
+/// Finds the first generated output and the usage chunk in a response body
+/// fed to it chunk by chunk
+#[derive(Default)]
+pub struct UsageTap {
+    line: Vec<u8>,
+    // Set when the current line outgrew MAX_LINE and is being skipped
+    overflowed: bool,
+    output_seen: bool,
+    usage: Option<Usage>,
+}
+
+impl UsageTap {
+    pub fn feed(&mut self, mut chunk: &[u8]) {
+        while let Some(newline) = chunk.iter().position(|&b| b == b'\n') {
+            self.push(&chunk[..newline]);
+            self.end_line();
+            chunk = &chunk[newline + 1..];
+        }
+        self.push(chunk);
+    }
+
+    /// Whether a line with generated output has been fed
+    pub fn output_seen(&self) -> bool {
+        self.output_seen
+    }
+
+    /// The usage found, once the whole body has been fed
+    pub fn finish(&mut self) -> Option<Usage> {
+        // A `"stream": false` body need not end with a newline
+        self.end_line();
+        self.usage
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        if self.overflowed {
+            return;
+        }
+        if self.line.len() + bytes.len() > MAX_LINE {
+            self.overflowed = true;
+            self.line = Vec::new();
+            return;
+        }
+        self.line.extend_from_slice(bytes);
+    }
+
+    fn end_line(&mut self) {
+        // Lines are only parsed in full until the first token turns up
+        if !self.output_seen && !self.overflowed && !self.line.is_empty() {
+            self.output_seen = serde_json::from_slice::<OutputChunk>(&self.line)
+                .is_ok_and(|chunk| chunk.has_output());
+        }
+        // Only the final chunk has token counts, so skip parsing every other line
+        if !self.overflowed && contains(&self.line, b"eval_count") {
+            if let Ok(chunk) = serde_json::from_slice::<FinalChunk>(&self.line) {
+                if chunk.done {
+                    self.usage = Some(chunk.usage);
+                }
+            }
+        }
+        self.line.clear();
+        self.overflowed = false;
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+/// Running totals for one client, model and upstream
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct UsageEntry {
+    pub client: String,
+    pub model: String,
+    pub upstream: String,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+/// Token usage since the proxy started, served at `/usage` on the admin address
+#[derive(Default)]
+pub struct UsageTotals {
+    entries: Mutex<BTreeMap<(String, String, String), UsageEntry>>,
+}
+
+impl UsageTotals {
+    pub fn record(&self, client: &str, model: &str, upstream: &str, usage: &Usage) {
+        let key = (client.to_string(), model.to_string(), upstream.to_string());
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(key).or_insert_with(|| UsageEntry {
+            client: client.to_string(),
+            model: model.to_string(),
+            upstream: upstream.to_string(),
+            ..Default::default()
+        });
+        entry.requests += 1;
+        entry.prompt_tokens += usage.prompt_eval_count;
+        entry.completion_tokens += usage.eval_count;
+    }
+
+    pub fn snapshot(&self) -> Vec<UsageEntry> {
+        self.entries.lock().unwrap().values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FINAL: &str =
+        r#"{"model":"llama3","done":true,"prompt_eval_count":12,"eval_count":34,"eval_duration":2000000000}"#;
+
+    fn expected() -> Usage {
+        Usage {
+            prompt_eval_count: 12,
+            eval_count: 34,
+            eval_duration: 2_000_000_000,
+            ..Usage::default()
+        }
+    }
+
+    #[test]
+    fn final_chunk_split_across_feeds() {
+        let mut tap = UsageTap::default();
+        tap.feed(b"{\"message\":{\"role\":\"assistant\",\"content\":\"Hi\"},\"done\":false}\n");
+        assert!(tap.output_seen());
+        let (head, tail) = FINAL.split_at(40);
+        tap.feed(head.as_bytes());
+        tap.feed(format!("{}\n", tail).as_bytes());
+        assert_eq!(tap.finish(), Some(expected()));
+    }
+
+    #[test]
+    fn unstreamed_body_without_a_newline() {
+        let mut tap = UsageTap::default();
+        let body = FINAL.replace("\"done\"", "\"response\":\"Hi\",\"done\"");
+        tap.feed(body.as_bytes());
+        assert!(!tap.output_seen());
+        assert_eq!(tap.finish(), Some(expected()));
+        assert!(tap.output_seen());
+    }
+
+    #[test]
+    fn unfinished_stream_has_no_usage() {
+        let mut tap = UsageTap::default();
+        tap.feed(b"{\"response\":\"\",\"done\":false,\"eval_count\":3}\n");
+        assert!(!tap.output_seen());
+        assert_eq!(tap.finish(), None);
+    }
+
+    #[test]
+    fn oversized_line_is_given_up_on() {
+        let mut tap = UsageTap::default();
+        let huge = format!(
+            r#"{{"done":true,"eval_count":1,"context":[{}0]}}"#,
+            "1,".repeat(MAX_LINE / 2)
+        );
+        // Fed in pieces, the way a remote would send it
+        for piece in huge.as_bytes().chunks(64 * 1024) {
+            tap.feed(piece);
+        }
+        assert!(tap.line.is_empty());
+        tap.feed(b"\n");
+        assert_eq!(tap.finish(), None);
+
+        // The line after it is read as usual
+        tap.feed(format!("{}\n", FINAL).as_bytes());
+        assert_eq!(tap.finish(), Some(expected()));
+    }
+}
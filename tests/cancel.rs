@@ -0,0 +1,170 @@
+//! The remote request is dropped as soon as the client goes away, so the
+//! remote stops generating tokens nobody will read
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+mod common;
+
+use common::Agent;
+
+const BODY: &str = r#"{"model":"llama3","prompt":"Why is the sky blue?"}"#;
+
+// How soon after the client leaves the remote connection has to be closed
+const PROMPTLY: Duration = Duration::from_secs(1);
+
+// Sends a generate request and returns the open connection
+async fn generate(agent: &Agent) -> TcpStream {
+    let mut conn = TcpStream::connect(agent.addr).await.unwrap();
+    let request = format!(
+        "POST /api/generate HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\n\r\n{}",
+        BODY.len(),
+        BODY
+    );
+    conn.write_all(request.as_bytes()).await.unwrap();
+    conn
+}
+
+// Waits for the cancellation counter of a stage to reach one
+async fn wait_for_cancellation(agent: &Agent, stage: &str) {
+    let label = format!("stage=\"{}\"", stage);
+    for _ in 0..40 {
+        let metrics = metrics(agent).await;
+        let counted = metrics
+            .lines()
+            .filter(|line| line.starts_with("ollama_agent_requests_cancelled_total{") && line.contains(&label))
+            .any(|line| line.ends_with(" 1"));
+        if counted {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("no cancellation was counted for stage {}:\n{}", stage, metrics(agent).await);
+}
+
+async fn metrics(agent: &Agent) -> String {
+    let mut conn = TcpStream::connect(agent.admin_addr).await.unwrap();
+    conn.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+// What the fake remote saw of the proxy's request
+struct Remote {
+    addr: SocketAddr,
+    received: oneshot::Receiver<()>,
+    closed: oneshot::Receiver<Instant>,
+}
+
+// A remote that takes one generate request and reports when the proxy closes
+// the connection; it streams tokens until then, or never answers at all
+async fn remote(streams: bool) -> Remote {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (received_tx, received) = oneshot::channel();
+    let (closed_tx, closed) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        read_request(&mut conn).await;
+        let _ = received_tx.send(());
+        if streams {
+            conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nTransfer-Encoding: chunked\r\n\r\n")
+                .await
+                .unwrap();
+        }
+
+        let mut tokens = tokio::time::interval(Duration::from_millis(50));
+        let mut buf = [0; 1024];
+        loop {
+            tokio::select! {
+                read = conn.read(&mut buf) => {
+                    if matches!(read, Ok(0) | Err(_)) {
+                        break;
+                    }
+                }
+                _ = tokens.tick(), if streams => {
+                    let line = "{\"model\":\"llama3\",\"response\":\"token\",\"done\":false}\n";
+                    let chunk = format!("{:x}\r\n{}\r\n", line.len(), line);
+                    if conn.write_all(chunk.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        let _ = closed_tx.send(Instant::now());
+    });
+    Remote { addr, received, closed }
+}
+
+// Reads the request head and a body of known length
+async fn read_request(conn: &mut TcpStream) {
+    let mut request = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n = conn.read(&mut buf).await.unwrap();
+        request.extend_from_slice(&buf[..n]);
+        if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&request[..end]).to_ascii_lowercase();
+            let length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .map_or(0, |length| length.trim().parse().unwrap());
+            if request.len() >= end + 4 + length {
+                return;
+            }
+        }
+        assert!(n > 0, "the proxy closed the connection before sending the whole request");
+    }
+}
+
+async fn assert_closed_promptly(closed: oneshot::Receiver<Instant>, client_left: Instant) {
+    let closed = tokio::time::timeout(Duration::from_secs(5), closed)
+        .await
+        .expect("the remote connection was still open 5s after the client went away")
+        .unwrap();
+    let after = closed.saturating_duration_since(client_left);
+    assert!(after < PROMPTLY, "the remote connection was closed {:?} after the client went away", after);
+}
+
+#[tokio::test]
+async fn closes_the_remote_stream_when_the_client_goes_away() {
+    let remote = remote(true).await;
+    let agent = Agent::start(remote.addr, &[]).await;
+
+    let mut conn = generate(&agent).await;
+    let mut received = Vec::new();
+    let mut buf = [0; 1024];
+    while !received.windows(7).any(|window| window == b"\"token\"") {
+        let n = conn.read(&mut buf).await.unwrap();
+        assert!(n > 0, "the proxy closed the connection before streaming");
+        received.extend_from_slice(&buf[..n]);
+    }
+    drop(conn);
+
+    assert_closed_promptly(remote.closed, Instant::now()).await;
+    wait_for_cancellation(&agent, "streaming").await;
+}
+
+#[tokio::test]
+async fn cancels_the_remote_request_when_the_client_goes_away_before_a_response() {
+    let remote = remote(false).await;
+    let agent = Agent::start(remote.addr, &[]).await;
+
+    let conn = generate(&agent).await;
+    tokio::time::timeout(Duration::from_secs(5), remote.received)
+        .await
+        .expect("the request never reached the remote")
+        .unwrap();
+    drop(conn);
+
+    assert_closed_promptly(remote.closed, Instant::now()).await;
+    wait_for_cancellation(&agent, "upstream").await;
+}
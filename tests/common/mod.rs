@@ -0,0 +1,64 @@
+//! Running the proxy binary for the integration tests
+
+// Not every test uses every helper
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+
+/// The proxy binary, stopped when dropped
+pub struct Agent {
+    child: Child,
+    pub addr: SocketAddr,
+    pub admin_addr: SocketAddr,
+}
+
+impl Agent {
+    /// Starts the proxy in front of `remote` with extra flags, once it is listening
+    pub async fn start(remote: SocketAddr, args: &[&str]) -> Agent {
+        let (addr, admin_addr) = (free_addr(), free_addr());
+        let child = Command::new(env!("CARGO_BIN_EXE_ollama-agent"))
+            .arg("--local-addr")
+            .arg(addr.to_string())
+            .arg("--remote-url")
+            .arg(format!("http://{}", remote))
+            .arg("--admin-addr")
+            .arg(admin_addr.to_string())
+            .args(args)
+            .env_remove("OLLAMA_API_KEY")
+            .env_remove("OLLAMA_AGENT_CONFIG")
+            .env_remove("OLLAMA_AGENT_USAGE_DB")
+            .env_remove("OTEL_EXPORTER_OTLP_ENDPOINT")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start ollama-agent");
+        let agent = Agent {
+            child,
+            addr,
+            admin_addr,
+        };
+        for _ in 0..100 {
+            if TcpStream::connect(addr).await.is_ok() && TcpStream::connect(admin_addr).await.is_ok() {
+                return agent;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("ollama-agent did not start listening on {}", addr);
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// An address nothing is listening on
+pub fn free_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
@@ -0,0 +1,149 @@
+//! Spans are exported over OTLP/HTTP, and the client's trace is carried on
+//! to the remote
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use serde_json::Value;
+use tokio::sync::mpsc;
+
+mod common;
+
+use common::Agent;
+
+const TRACE_ID: &str = "0af7651916cd43dd8448eb211c80319c";
+const CLIENT_SPAN_ID: &str = "b7ad6b7169203331";
+
+const CHAT_RESPONSE: &str = concat!(
+    r#"{"model":"llama3","message":{"role":"assistant","content":"Hi"},"done":false}"#,
+    "\n",
+    r#"{"model":"llama3","message":{"role":"assistant","content":""},"done":true,"#,
+    r#""prompt_eval_count":12,"eval_count":5,"eval_duration":100000000}"#,
+    "\n",
+);
+
+// Answers a request given its body, with something to pass on to the test
+type Handler<T> = fn(&Request<Body>, Vec<u8>) -> (T, Response<Body>);
+
+// Serves `handle` on a free port, passing on what each request carried
+async fn serve<T: Send + 'static>(handle: Handler<T>) -> (SocketAddr, mpsc::UnboundedReceiver<T>) {
+    let (seen, received) = mpsc::unbounded_channel();
+    let make_service = make_service_fn(move |_| {
+        let seen = seen.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let seen = seen.clone();
+                async move {
+                    let (parts, body) = req.into_parts();
+                    let body = hyper::body::to_bytes(body).await.unwrap().to_vec();
+                    let (item, response) = handle(&Request::from_parts(parts, Body::empty()), body);
+                    let _ = seen.send(item);
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    (addr, received)
+}
+
+// A remote that answers chat requests, passing on the traceparent it was sent
+fn remote(req: &Request<Body>, _body: Vec<u8>) -> (Option<String>, Response<Body>) {
+    let traceparent = req
+        .headers()
+        .get("traceparent")
+        .map(|value| value.to_str().unwrap().to_string());
+    let response = Response::builder()
+        .header("content-type", "application/x-ndjson")
+        .body(Body::from(CHAT_RESPONSE))
+        .unwrap();
+    (traceparent, response)
+}
+
+// A collector that passes on the spans of every export
+fn collector(req: &Request<Body>, body: Vec<u8>) -> (Vec<Value>, Response<Body>) {
+    assert_eq!(req.uri().path(), "/v1/traces");
+    let export: Value = serde_json::from_slice(&body).unwrap();
+    let spans = export["resourceSpans"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .flat_map(|resource| resource["scopeSpans"].as_array().unwrap())
+        .flat_map(|scope| scope["spans"].as_array().unwrap().clone())
+        .collect();
+    (spans, Response::new(Body::from("{}")))
+}
+
+fn find<'a>(spans: &'a [Value], name: &str) -> &'a Value {
+    spans
+        .iter()
+        .find(|span| span["name"] == name)
+        .unwrap_or_else(|| panic!("no {} span in {:#?}", name, spans))
+}
+
+fn attribute<'a>(span: &'a Value, key: &str) -> &'a Value {
+    let attribute = span["attributes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|attribute| attribute["key"] == key)
+        .unwrap_or_else(|| panic!("no {} attribute on {:#}", key, span));
+    &attribute["value"]
+}
+
+#[tokio::test]
+async fn exports_the_spans_of_a_request_in_the_clients_trace() {
+    let (remote_addr, mut traceparents) = serve(remote).await;
+    let (collector_addr, mut exports) = serve(collector).await;
+    let endpoint = format!("http://{}", collector_addr);
+    let agent = Agent::start(remote_addr, &["--otlp-endpoint", &endpoint]).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/api/chat", agent.addr))
+        .header("traceparent", format!("00-{}-{}-01", TRACE_ID, CLIENT_SPAN_ID))
+        .body(r#"{"model":"llama3","messages":[{"role":"user","content":"Hello"}]}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), CHAT_RESPONSE);
+
+    // The remote joins the client's trace under a span of the proxy's own
+    let traceparent = traceparents.recv().await.unwrap().expect("no traceparent was sent upstream");
+    let parts: Vec<&str> = traceparent.split('-').collect();
+    assert_eq!(parts.len(), 4, "malformed traceparent {}", traceparent);
+    assert_eq!((parts[0], parts[1], parts[3]), ("00", TRACE_ID, "01"));
+    assert_ne!(parts[2], CLIENT_SPAN_ID);
+
+    // Spans go out in batches every couple of seconds
+    let mut spans = Vec::new();
+    while spans.len() < 4 {
+        let exported = tokio::time::timeout(Duration::from_secs(10), exports.recv())
+            .await
+            .expect("no spans were exported");
+        spans.extend(exported.unwrap());
+    }
+    assert!(spans.iter().all(|span| span["traceId"] == TRACE_ID), "{:#?}", spans);
+
+    let inbound = find(&spans, "POST /api/chat");
+    assert_eq!(inbound["parentSpanId"], CLIENT_SPAN_ID);
+    assert_eq!(attribute(inbound, "gen_ai.request.model")["stringValue"], "llama3");
+    assert_eq!(attribute(inbound, "gen_ai.usage.input_tokens")["intValue"], "12");
+    assert_eq!(attribute(inbound, "gen_ai.usage.output_tokens")["intValue"], "5");
+    assert_eq!(attribute(inbound, "http.response.status_code")["intValue"], "200");
+
+    let upstream = find(&spans, "upstream request");
+    assert_eq!(upstream["parentSpanId"], inbound["spanId"]);
+    assert_eq!(upstream["spanId"], parts[2]);
+    assert_eq!(attribute(upstream, "http.response.status_code")["intValue"], "200");
+
+    let first_byte = find(&spans, "first byte");
+    assert_eq!(first_byte["parentSpanId"], inbound["spanId"]);
+    let stream = find(&spans, "stream");
+    assert_eq!(stream["parentSpanId"], inbound["spanId"]);
+    assert_eq!(attribute(stream, "ollama_agent.outcome")["stringValue"], "completed");
+}